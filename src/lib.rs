@@ -0,0 +1,9 @@
+//! Creating and reading the `.vdf` and `.mod` archives of the ZenGin based games made by Piranha Bytes.
+//!
+//! Archives are built from a directory, a yaml script or piece by piece with `VdfsBuilder`,
+//! and read back with `Vdfs::from_file`. The `vdfs` binary puts a command line on top of
+//! this, adding argument parsing and console output.
+
+mod vdfs;
+
+pub use crate::vdfs::*;
@@ -1,61 +1,936 @@
-mod vdfs;
+use anyhow::{bail, Context, Result};
+use std::{
+    fs,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
-use std::{path::PathBuf, process::exit};
-
-use clap::Parser;
-use vdfs::Vdfs;
+use clap::{Args, Parser, Subcommand};
+use vdfs::{
+    read_order_file, truncate_encoded, volume_path, BuildOptions, CatalogLayout, DecodeFallback,
+    Encoding, Endianness, ExtractOptions, FileSystemNode, FlattenCollision, OnConflict,
+    PathRewrite, ProgressSink, ReadOptions, TimeZone, TreeFilter, Vdfs, VdfsBuilder, VdfsScript,
+    COMMENT_SIZE, SIGNATURE_SIZE,
+};
 
 #[derive(Parser, Debug)]
-#[command(term_width = 0, arg_required_else_help(true))]
-struct Args {
+#[command(
+    term_width = 0,
+    arg_required_else_help(true),
+    args_conflicts_with_subcommands(true),
+    subcommand_negates_reqs(true)
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Packing is the default when no subcommand is given
+    #[command(flatten)]
+    pack: PackArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Pack a directory or a yaml or json script into an archive (default)
+    Pack(Box<PackArgs>),
+
+    /// List the contents of an archive
+    List {
+        /// The archive to list
+        #[arg(value_hint = clap::ValueHint::FilePath)]
+        archive: PathBuf,
+
+        /// How comment bytes that are not valid in the encoding are shown
+        #[arg(long, value_enum, default_value_t = DecodeFallback::Replace)]
+        comment_fallback: DecodeFallback,
+
+        /// Codepage of the names and the comment
+        #[arg(long, value_enum, default_value_t = Encoding::Cp1252)]
+        encoding: Encoding,
+
+        /// Fail on archives whose version is not 80 instead of only warning
+        #[arg(long)]
+        strict_version: bool,
+
+        /// Compare every file to the CRC32 stored in its attributes by --crc-in-attributes
+        #[arg(long)]
+        check_crc: bool,
+
+        /// Only show the entries of the archive root with the number and size of the files in each
+        #[arg(long)]
+        top_level: bool,
+
+        /// Show the comment, timestamp and size followed by a tree of all directories and files
+        #[arg(long, conflicts_with = "top_level")]
+        tree: bool,
+    },
+
+    /// Extract the contents of an archive
+    Extract {
+        /// The archive to extract
+        #[arg(value_hint = clap::ValueHint::FilePath)]
+        archive: PathBuf,
+
+        /// The directory to extract into, defaults to one named after the archive
+        #[arg(short = 'o', long, value_name = "DIR", value_hint = clap::ValueHint::DirPath)]
+        output_dir: Option<PathBuf>,
+
+        /// Restore the original spelling of names from a case map written by --emit-case-map
+        #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+        case_map: Option<PathBuf>,
+
+        /// Codepage of the names and the comment
+        #[arg(long, value_enum, default_value_t = Encoding::Cp1252)]
+        encoding: Encoding,
+
+        /// Salvage a truncated archive, extracting only the files whose data is complete
+        #[arg(long)]
+        recover: bool,
+
+        /// Compare every file to the CRC32 stored in its attributes by --crc-in-attributes
+        #[arg(long)]
+        check_crc: bool,
+
+        /// Give the extracted files the archive's timestamp as modification time
+        #[arg(long)]
+        restore_mtime: bool,
+
+        /// Make files marked read-only in the archive read-only on disk
+        #[arg(long, conflicts_with = "check_crc")]
+        restore_attributes: bool,
+
+        /// Write every file directly into the output directory, leaving out all directories
+        #[arg(long)]
+        flatten: bool,
+
+        /// What happens when flattening gives two files the same name
+        #[arg(long, value_enum, default_value_t = FlattenCollision::Error, requires = "flatten")]
+        on_collision: FlattenCollision,
+    },
+
+    /// Compare the files of an archive to the ones in a directory, like the one it was packed from
+    Compare {
+        /// The archive to compare
+        #[arg(value_hint = clap::ValueHint::FilePath)]
+        archive: PathBuf,
+
+        /// The directory to compare it to
+        #[arg(value_hint = clap::ValueHint::DirPath)]
+        dir: PathBuf,
+
+        /// Compare the files byte by byte instead of only by size
+        #[arg(long)]
+        contents: bool,
+
+        /// Codepage of the names and the comment
+        #[arg(long, value_enum, default_value_t = Encoding::Cp1252)]
+        encoding: Encoding,
+    },
+
+    /// List the files added, removed and changed between two archives, like two releases of a mod
+    Diff {
+        /// The older archive
+        #[arg(value_hint = clap::ValueHint::FilePath)]
+        old: PathBuf,
+
+        /// The newer archive
+        #[arg(value_hint = clap::ValueHint::FilePath)]
+        new: PathBuf,
+
+        /// Codepage of the names and the comment
+        #[arg(long, value_enum, default_value_t = Encoding::Cp1252)]
+        encoding: Encoding,
+    },
+
+    /// Check an archive for consistency
+    Verify {
+        /// The archive to verify
+        #[arg(value_hint = clap::ValueHint::FilePath)]
+        archive: PathBuf,
+    },
+}
+
+#[derive(Args, Debug)]
+struct PackArgs {
     /// The base directory override
     #[arg(short = 'b', long, value_name = "DIR", value_hint = clap::ValueHint::DirPath)]
-    base_directory: Option<std::path::PathBuf>,
+    base_directory: Option<PathBuf>,
 
-    /// The output file override
+    /// The output file override, `-` writes the archive to stdout
     #[arg(short = 'o', long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
-    output_file: Option<std::path::PathBuf>,
+    output_file: Option<PathBuf>,
+
+    /// Write the archive into this directory, named after the packed directory or script
+    #[arg(long, value_name = "DIR", value_hint = clap::ValueHint::DirPath, conflicts_with = "output_file")]
+    output_dir: Option<PathBuf>,
 
     /// Comment to be added to the volume
     #[arg(short = 'c', long)]
     comment: Option<String>,
 
-    /// The yaml script or base directory
-    #[arg()]
-    input: String,
+    /// Read the comment from FILE, it is cut off where it no longer fits into the header
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath, conflicts_with = "comment")]
+    comment_file: Option<PathBuf>,
+
+    /// Leave out files matched by .gitignore files in the packed tree
+    #[arg(long)]
+    respect_gitignore: bool,
+
+    /// Pack what symbolic links point to instead of skipping them
+    #[arg(long)]
+    follow_symlinks: bool,
+
+    /// Pack files and directories whose name starts with a dot, version control directories are skipped anyway
+    #[arg(long)]
+    include_hidden: bool,
+
+    /// Leave out files modified less than AGE ago, like 5s, 10m or 1h
+    #[arg(long, value_name = "AGE", value_parser = parse_duration)]
+    exclude_newer_than: Option<Duration>,
+
+    /// Leave out files last modified more than AGE ago, like 30m, 12h or 7d
+    #[arg(long, value_name = "AGE", value_parser = parse_duration)]
+    exclude_older_than: Option<Duration>,
+
+    /// Pack *.VDF and *.MOD files found in the base directory instead of skipping them
+    #[arg(long)]
+    include_archives: bool,
+
+    /// Fail instead of skipping entries that cannot be packed, like sockets or device nodes,
+    /// and when there are no files to pack at all
+    #[arg(long)]
+    strict: bool,
+
+    /// Add a listing of the archive contents as _CONTENTS.TXT
+    #[arg(long)]
+    embed_manifest: bool,
+
+    /// Pack directories without any files as entries of their own instead of leaving them out
+    #[arg(long, conflicts_with = "split")]
+    keep_empty_dirs: bool,
+
+    /// Put everything into a single top-level directory NAME
+    #[arg(long, value_name = "NAME")]
+    root_name: Option<String>,
+
+    /// Put every file directly into the archive root, dropping all directories
+    #[arg(long)]
+    flatten: bool,
+
+    /// Sort each directory strictly by name, for loaders that binary-search the catalog
+    #[arg(long)]
+    sorted_catalog: bool,
+
+    /// Order the entries of every directory like the logical paths listed in FILE, one per line
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath, conflicts_with = "sorted_catalog")]
+    order_file: Option<PathBuf>,
+
+    /// Keep entries in the order they are found instead of sorting them, fails if that order is ambiguous
+    #[arg(long, conflicts_with_all = ["sorted_catalog", "flatten", "embed_manifest"])]
+    no_sort: bool,
+
+    /// Store files with identical contents only once, not every loader expects shared offsets
+    #[arg(long)]
+    dedup: bool,
+
+    /// Read the archive back after writing it and fail if it differs from what was packed
+    #[arg(long)]
+    verify_after_pack: bool,
+
+    /// Only print the entries, sizes and tree of the archive, nothing is written and no file is read
+    #[arg(long, conflicts_with_all = ["dedup", "crc_in_attributes", "verify_after_pack", "split", "update", "check_names"])]
+    dry_run: bool,
+
+    /// Order of the entries within each directory, sorted-catalog implies interleaved
+    #[arg(long, value_enum, default_value_t = CatalogLayout::Grouped)]
+    catalog_layout: CatalogLayout,
+
+    /// Store the CRC32 of every file in the attributes of its catalog entry
+    #[arg(long)]
+    crc_in_attributes: bool,
+
+    /// Read the files on all cores for --dedup and --crc-in-attributes, the archive comes out the same
+    #[arg(long)]
+    parallel_read: bool,
+
+    /// Print how many files are done while building the catalog and how much data is written, once a second
+    #[arg(long)]
+    progress: bool,
+
+    /// Mark read-only files as such in the attributes of their catalog entry
+    #[arg(long, conflicts_with = "crc_in_attributes")]
+    preserve_attributes: bool,
+
+    /// Write names in their original case instead of uppercase, the engine expects uppercase names
+    #[arg(long)]
+    preserve_case: bool,
+
+    /// Fail when two files anywhere in the archive share a name, for loaders that ignore directories
+    #[arg(long)]
+    unique_basenames: bool,
+
+    /// Pad the file data to SIZE bytes so files can later be replaced in place (K, M and G suffixes allowed)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, conflicts_with = "split")]
+    reserve: Option<u64>,
+
+    /// Store KEY=VALUE in a metadata block between header and catalog, shown by list, can be repeated
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_metadata)]
+    metadata: Vec<(String, String)>,
+
+    /// Fail unless the archive contains exactly N files, the listing from --embed-manifest included
+    #[arg(long, value_name = "N", conflicts_with = "split")]
+    expect_files: Option<u32>,
+
+    /// Rewrite the path of every file with a sed style s/pattern/replacement/ rule, can be repeated
+    #[arg(long, value_name = "RULE")]
+    path_rewrite: Vec<PathRewrite>,
+
+    /// Write the original spelling of every path to FILE, the archive only stores uppercase names
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    emit_case_map: Option<PathBuf>,
+
+    /// Write the CRC32 of every file to FILE, for comparing builds with a plain diff
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    emit_checksums: Option<PathBuf>,
+
+    /// Spread the files over numbered archives (NAME.001.VDF, ...) of at most SIZE bytes each,
+    /// SIZE takes an optional K, M or G suffix
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, conflicts_with_all = ["emit_case_map", "emit_checksums"])]
+    split: Option<u64>,
+
+    /// Print which glob of the script or --glob selected every file, and which exclude glob left one out
+    #[arg(long)]
+    explain: bool,
+
+    /// DEBUG ONLY: byte order of the header and catalog numbers, big gives an archive no loader can read
+    #[arg(long, value_enum, default_value_t = Endianness::Little, conflicts_with = "verify_after_pack")]
+    debug_endianness: Endianness,
+
+    /// Only report file names the engine may have trouble with, nothing is written
+    #[arg(long)]
+    check_names: bool,
+
+    /// Codepage the names and the comment are stored in
+    #[arg(long, value_enum, default_value_t = Encoding::Cp1252)]
+    encoding: Encoding,
+
+    /// Clock used for the archive timestamp, the original tools used local time
+    #[arg(long, value_enum, default_value_t = TimeZone::Local)]
+    time_zone: TimeZone,
+
+    /// Write 0 as the archive timestamp, like some legacy tools did
+    #[arg(long, conflicts_with = "time_zone")]
+    no_timestamp: bool,
+
+    /// Write SECONDS since the Unix epoch (UTC) as the archive timestamp instead of the current time,
+    /// defaults to SOURCE_DATE_EPOCH when it is set
+    #[arg(long, value_name = "SECONDS", value_parser = parse_timestamp, conflicts_with_all = ["time_zone", "no_timestamp"])]
+    timestamp: Option<u32>,
+
+    /// Write N as the header version instead of 80, for engine forks that expect another one
+    #[arg(long, value_name = "N")]
+    header_version: Option<u32>,
+
+    /// Write TEXT as the header signature instead of PSVDSC_V2.00, at most 16 ASCII characters.
+    /// Shorter ones are padded with the line breaks the standard signature ends with
+    #[arg(long, value_name = "TEXT", value_parser = parse_signature)]
+    signature: Option<[u8; SIGNATURE_SIZE]>,
+
+    /// Add the files to ARCHIVE instead of building a new one, written back to it unless -o is given
+    #[arg(long, value_name = "ARCHIVE", value_hint = clap::ValueHint::FilePath, conflicts_with_all = ["split", "output_dir"])]
+    update: Option<PathBuf>,
+
+    /// What happens to files ARCHIVE already has when updating it
+    #[arg(long, value_enum, default_value_t = OnConflict::Error, requires = "update")]
+    on_conflict: OnConflict,
+
+    /// Pack the files below the base directory (-b) matching PATTERN instead of a script or directory, can be repeated
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        requires = "base_directory",
+        conflicts_with = "input"
+    )]
+    glob: Vec<String>,
+
+    /// The yaml or json script or base directory
+    #[arg(required_unless_present = "glob")]
+    input: Option<PathBuf>,
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
-
-    if !args.input.is_empty() {
-        let mut path = PathBuf::from(args.input);
-        if path.is_dir() {
-            Vdfs::from_dir(&mut path)
-                .add_comment(args.comment.as_deref())
-                .save_to_file(&match args.output_file {
-                    Some(p) => p,
-                    None => {
-                        path.push("DEFAULT.VDF");
-                        path
-                    }
-                })?;
-        } else if path.is_file() {
-            Vdfs::from_script(
-                &path,
-                &args.base_directory,
-                &args.output_file,
-                &args.comment,
-            )?;
+    let cli = Cli::parse();
+
+    match cli.command.unwrap_or(Command::Pack(Box::new(cli.pack))) {
+        Command::Pack(args) => pack(*args),
+        Command::List {
+            archive,
+            comment_fallback,
+            encoding,
+            strict_version,
+            check_crc,
+            top_level,
+            tree,
+        } => {
+            let options = ReadOptions {
+                comment_fallback,
+                encoding,
+                strict_version,
+                check_crc,
+                ..Default::default()
+            };
+            let vdfs = Vdfs::from_file_with(&archive, &options)?;
+            if tree {
+                let info = vdfs.header_info();
+                println!("Comment: {}", info.comment);
+                match info.timestamp {
+                    Some(time) => println!("Timestamp: {}", time),
+                    None => println!("Timestamp: none"),
+                }
+                println!(
+                    "Size: {} bytes in {} files",
+                    vdfs.total_extracted_size(),
+                    info.num_entries
+                );
+                vdfs.write_tree(&mut std::io::stdout())?;
+            } else if top_level {
+                for entry in vdfs.top_level_summary() {
+                    let name = if entry.is_dir {
+                        format!("{}/", entry.name)
+                    } else {
+                        entry.name
+                    };
+                    println!("{:>12} {:>8} files  {}", entry.bytes, entry.files, name);
+                }
+            } else {
+                print!("{}", vdfs);
+            }
+            Ok(())
+        }
+        Command::Extract {
+            archive,
+            output_dir,
+            case_map,
+            encoding,
+            recover,
+            check_crc,
+            restore_mtime,
+            restore_attributes,
+            flatten,
+            on_collision,
+        } => {
+            let options = ReadOptions {
+                encoding,
+                recover,
+                check_crc,
+                ..Default::default()
+            };
+            let mut vdfs = Vdfs::from_file_with(&archive, &options)?;
+            if let Some(case_map) = case_map {
+                vdfs.apply_case_map(&case_map)?;
+            }
+            let output_dir = match output_dir {
+                Some(dir) => dir,
+                None => match archive.file_stem() {
+                    Some(stem) => PathBuf::from(stem),
+                    None => bail!("Cannot derive a directory name from {}.", archive.display()),
+                },
+            };
+            let extract_options = ExtractOptions {
+                skip_truncated: recover,
+                restore_mtime,
+                restore_attributes,
+                flatten,
+                on_collision,
+            };
+            vdfs.extract_with(&output_dir, &extract_options)
+        }
+        Command::Compare {
+            archive,
+            dir,
+            contents,
+            encoding,
+        } => {
+            let options = ReadOptions {
+                encoding,
+                ..Default::default()
+            };
+            let vdfs = Vdfs::from_file_with(&archive, &options)?;
+            let report = vdfs.compare_to_dir(&dir, contents)?;
+            println!("{}", report);
+            if !report.is_identical() {
+                bail!("{} differs from {}.", archive.display(), dir.display());
+            }
+            Ok(())
+        }
+        Command::Diff { old, new, encoding } => {
+            let options = ReadOptions {
+                encoding,
+                ..Default::default()
+            };
+            let old = Vdfs::from_file_with(&old, &options)?;
+            let new = Vdfs::from_file_with(&new, &options)?;
+            println!("{}", old.diff(&new));
+            Ok(())
+        }
+        Command::Verify { archive } => {
+            let vdfs = Vdfs::from_file(&archive)?;
+            vdfs.verify()
+                .with_context(|| format!("{} is inconsistent", archive.display()))?;
+            eprintln!("[INFO] {} is consistent.", archive.display());
+            Ok(())
+        }
+    }
+}
+
+fn pack(mut args: PackArgs) -> Result<()> {
+    if let Some(comment_file) = &args.comment_file {
+        args.comment = Some(read_comment_file(comment_file, args.encoding)?);
+    }
+    let globs: Vec<&str> = args.glob.iter().map(String::as_str).collect();
+    // With --glob the base directory is what gets packed
+    let input = if globs.is_empty() {
+        args.input
+    } else {
+        args.base_directory.clone()
+    };
+    let Some(path) = input else {
+        bail!("Please provide a script or a base directory.");
+    };
+    if args.output_file.as_deref() == Some(Path::new("-"))
+        && (args.verify_after_pack || args.split.is_some())
+    {
+        bail!("An archive written to stdout can neither be verified nor split.");
+    }
+
+    let timestamp = match (args.timestamp, std::env::var("SOURCE_DATE_EPOCH")) {
+        (Some(timestamp), _) => Some(timestamp),
+        (None, Ok(epoch)) if !args.no_timestamp => {
+            Some(parse_timestamp(&epoch).map_err(|e| anyhow::anyhow!("SOURCE_DATE_EPOCH: {}", e))?)
+        }
+        _ => None,
+    };
+
+    let mut options = BuildOptions {
+        tree_filter: TreeFilter::default()
+            .respect_gitignore(args.respect_gitignore)
+            .follow_symlinks(args.follow_symlinks)
+            .include_hidden(args.include_hidden)
+            .include_archives(args.include_archives)
+            .exclude_newer_than(args.exclude_newer_than)
+            .exclude_older_than(args.exclude_older_than)
+            .strict(args.strict)
+            .keep_order(args.no_sort)
+            .explain(args.explain),
+        embed_manifest: args.embed_manifest,
+        time_zone: args.time_zone,
+        no_timestamp: args.no_timestamp,
+        timestamp,
+        version: args.header_version,
+        signature: args.signature,
+        path_rewrites: args.path_rewrite,
+        keep_empty_dirs: args.keep_empty_dirs,
+        root_name: args.root_name,
+        flatten: args.flatten,
+        sorted_catalog: args.sorted_catalog,
+        catalog_layout: args.catalog_layout,
+        encoding: args.encoding,
+        dedup: args.dedup,
+        crc_in_attributes: args.crc_in_attributes,
+        parallel_read: args.parallel_read,
+        preserve_attributes: args.preserve_attributes,
+        preserve_case: args.preserve_case,
+        verify_after_pack: args.verify_after_pack,
+        unique_basenames: args.unique_basenames,
+        reserve: args.reserve,
+        metadata: args.metadata,
+        expect_files: args.expect_files,
+        case_map: args.emit_case_map,
+        checksums: args.emit_checksums,
+        debug_endianness: args.debug_endianness,
+        progress: args
+            .progress
+            .then(|| Arc::new(ConsoleProgress::new()) as Arc<dyn ProgressSink>),
+        order: match &args.order_file {
+            Some(path) => read_order_file(path)?,
+            None => Vec::new(),
+        },
+        // The priority list can only be given in a script
+        ..Default::default()
+    };
+
+    if args.check_names {
+        let tree = input_tree(&path, &globs, &args.base_directory, &mut options)?;
+        return check_names(&tree, args.encoding);
+    }
+
+    if let Some(archive) = args.update {
+        let tree = input_tree(&path, &globs, &args.base_directory, &mut options)?;
+        let read_options = ReadOptions {
+            encoding: options.encoding,
+            version: options.version,
+            signature: options.signature,
+            ..Default::default()
+        };
+        let existing = Vdfs::from_file_with(&archive, &read_options)?;
+        options.version.get_or_insert(existing.header.version());
+        options
+            .signature
+            .get_or_insert(*existing.header.signature());
+        let comment = match args.comment {
+            Some(comment) => comment,
+            None => existing.header_info().comment,
+        };
+        let vdfs = existing
+            .update_with(tree, args.on_conflict, &options)?
+            .add_comment(Some(&comment));
+        let output_file = args.output_file.unwrap_or(archive);
+        write_archive(&vdfs, &output_file)?;
+        if options.verify_after_pack {
+            vdfs.verify_written(&output_file)?;
+        }
+        if let Some(case_map) = &options.case_map {
+            vdfs.write_case_map(case_map)?;
+        }
+        if let Some(checksums) = &options.checksums {
+            vdfs.write_checksums(checksums)?;
+        }
+        return Ok(());
+    }
+
+    let output_file = match (args.output_file, &args.output_dir) {
+        (Some(p), _) => Some(p),
+        (None, Some(dir)) => {
+            if !args.dry_run {
+                fs::create_dir_all(dir)?;
+            }
+            Some(dir.join(derived_archive_name(&path)?))
+        }
+        (None, None) => None,
+    };
+
+    if let Some(limit) = args.split {
+        let (tree, output_file, comment) = if !globs.is_empty() || path.is_dir() {
+            let output_file = output_file.unwrap_or_else(|| path.join("DEFAULT.VDF"));
+            let tree = if globs.is_empty() {
+                Vdfs::tree_from_dir(&path, &mut options.tree_filter)
+            } else {
+                Vdfs::tree_from_globs(&path, &globs, &mut options.tree_filter)?
+            };
+            (tree, output_file, args.comment)
         } else {
-            eprintln!("This should not happen...");
-            exit(1);
+            let source = fs::read_to_string(&path)
+                .with_context(|| format!("Could not read the script {}", path.display()))?;
+            let script = VdfsScript::parse(&path, &source)
+                .with_context(|| format!("Could not parse {}", path.display()))?;
+            let Some(output_file) = output_file.or_else(|| {
+                (!script.file_path.as_os_str().is_empty()).then(|| script.file_path.clone())
+            }) else {
+                bail!("Empty output path in script file and no override was provided.");
+            };
+            let tree =
+                Vdfs::tree_from_script(&path, &args.base_directory, &mut options.tree_filter)?;
+            let comment = args.comment.or_else(|| Some(script.comment.to_string()));
+            options.priority = script.priority.iter().map(|p| p.to_string()).collect();
+            options.priority_placement = script.priority_placement;
+            options.path_rewrites.splice(0..0, script.path_rewrites()?);
+            (tree, output_file, comment)
+        };
+        return pack_volumes(&tree, limit, &output_file, comment.as_deref(), options);
+    }
+
+    let verify_after_pack = options.verify_after_pack;
+    let case_map = options.case_map.clone();
+    let checksums = options.checksums.clone();
+    let (vdfs, output_file) = if !globs.is_empty() || path.is_dir() {
+        let builder = if globs.is_empty() {
+            VdfsBuilder::new(options).add_dir(&path)
+        } else {
+            VdfsBuilder::new(options).add_globs(&path, &globs)
+        };
+        let vdfs = builder.set_comment(args.comment.as_deref()).build()?;
+        (
+            vdfs,
+            output_file.unwrap_or_else(|| path.join("DEFAULT.VDF")),
+        )
+    } else if path.is_file() {
+        // Known before the build so a script without a file_path fails right away
+        let output_file = script_output(&path, output_file)?;
+        let vdfs = Vdfs::from_script(&path, &args.base_directory, &args.comment, options)?;
+        (vdfs, output_file)
+    } else {
+        bail!("{} is neither a directory nor a script.", path.display());
+    };
+
+    if args.dry_run {
+        eprintln!("[INFO] Dry run, {} is not written.", output_file.display());
+        vdfs.write_plan(&mut std::io::stdout())?;
+        return Ok(());
+    }
+    write_archive(&vdfs, &output_file)?;
+    if verify_after_pack {
+        vdfs.verify_written(&output_file)?;
+    }
+    if let Some(case_map) = case_map {
+        vdfs.write_case_map(&case_map)?;
+    }
+    if let Some(checksums) = checksums {
+        vdfs.write_checksums(&checksums)?;
+    }
+    Ok(())
+}
+
+/// Saves `vdfs` to `output_file`, or streams it to stdout when that is `-`.
+fn write_archive(vdfs: &Vdfs, output_file: &PathBuf) -> Result<()> {
+    if output_file.as_os_str() == "-" {
+        let mut stdout = BufWriter::new(io::stdout().lock());
+        vdfs.write_to(&mut stdout)?;
+        stdout.flush()?;
+    } else {
+        vdfs.save_to_file(output_file)?;
+    }
+    Ok(())
+}
+
+/// The archive the script `path` is packed into, `output_file` or else the `file_path` of the script.
+fn script_output(path: &Path, output_file: Option<PathBuf>) -> Result<PathBuf> {
+    if let Some(output_file) = output_file {
+        return Ok(output_file);
+    }
+    let source = fs::read_to_string(path)
+        .with_context(|| format!("Could not read the script {}", path.display()))?;
+    let script = VdfsScript::parse(path, &source)
+        .with_context(|| format!("Could not parse {}", path.display()))?;
+    if script.file_path.as_os_str().is_empty() {
+        bail!("Empty output path in script file and no override was provided.");
+    }
+    Ok(script.file_path.to_path_buf())
+}
+
+/// The tree of the globs, directory or script `path` the pack command was given.
+fn input_tree(
+    path: &Path,
+    globs: &[&str],
+    base_directory: &Option<PathBuf>,
+    options: &mut BuildOptions,
+) -> Result<FileSystemNode> {
+    let path = path.to_path_buf();
+    let tree = if !globs.is_empty() {
+        Vdfs::tree_from_globs(&path, globs, &mut options.tree_filter)?
+    } else if path.is_dir() {
+        Vdfs::tree_from_dir(&path, &mut options.tree_filter)
+    } else {
+        Vdfs::tree_from_script(&path, base_directory, &mut options.tree_filter)?
+    };
+    if let Some(failure) = options.tree_filter.failure() {
+        bail!("{}", failure);
+    }
+    Ok(tree)
+}
+
+fn pack_volumes(
+    tree: &FileSystemNode,
+    limit: u64,
+    output_file: &Path,
+    comment: Option<&str>,
+    options: BuildOptions,
+) -> Result<()> {
+    let volumes = Vdfs::split_tree(tree, limit, &options)?;
+    eprintln!("[INFO] Splitting into {} volumes.", volumes.len());
+
+    for (i, vdfs) in volumes.into_iter().enumerate() {
+        let volume_file = volume_path(output_file, i + 1);
+        let vdfs = vdfs.add_comment(comment);
+        for (path, entry) in vdfs.entries() {
+            if !entry.is_dir() {
+                eprintln!("[INFO] {} -> {}", path, volume_file.display());
+            }
+        }
+        vdfs.save_to_file(&volume_file)?;
+        if options.verify_after_pack {
+            vdfs.verify_written(&volume_file)?;
         }
+    }
+    Ok(())
+}
+
+/// Parses sizes like `4096`, `700M` or `4G`, the suffixes are powers of 1024.
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, multiplier) = match size.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&size[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&size[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("{} is not a valid size.", size))
+}
+
+/// Parses ages like `90`, `5s`, `10m`, `1h` or `7d`, a number without a unit is seconds.
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let duration = duration.trim();
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (number, unit) = duration.split_at(split);
+    let multiplier = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "{} is not a valid age, use s, m, h or d as unit.",
+                duration
+            ))
+        }
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("{} is not a valid age.", duration))
+}
+
+/// Parses seconds since the Unix epoch into the DOS time of the header.
+fn parse_timestamp(secs: &str) -> Result<u32, String> {
+    secs.trim()
+        .parse::<i64>()
+        .ok()
+        .and_then(vdfs::unix_to_dos_time)
+        .ok_or_else(|| {
+            format!(
+                "{} is not a valid timestamp, it has to be seconds since 1970 in the years 1980 to 2107.",
+                secs
+            )
+        })
+}
+
+/// Pads a signature shorter than 16 bytes with `\n\r` pairs, like the end of `PSVDSC_V2.00\n\r\n\r`.
+fn parse_signature(text: &str) -> Result<[u8; SIGNATURE_SIZE], String> {
+    if !text.is_ascii() || text.len() > SIGNATURE_SIZE {
+        return Err(format!(
+            "{} is not a valid signature, it has to be at most {} ASCII characters.",
+            text, SIGNATURE_SIZE
+        ));
+    }
+    let mut signature = [0; SIGNATURE_SIZE];
+    signature[..text.len()].copy_from_slice(text.as_bytes());
+    for (byte, pad) in signature[text.len()..]
+        .iter_mut()
+        .zip(b"\n\r".iter().cycle())
+    {
+        *byte = *pad;
+    }
+    Ok(signature)
+}
+
+fn parse_metadata(pair: &str) -> Result<(String, String), String> {
+    match pair.split_once('=') {
+        Some((key, value)) if !key.is_empty() && !pair.contains('\n') => {
+            Ok((key.to_string(), value.to_string()))
+        }
+        _ => Err(format!("{} is not a single line KEY=VALUE pair.", pair)),
+    }
+}
+
+/// The text of `path` without its final line break, cut down to what fits into the header.
+fn read_comment_file(path: &Path, encoding: Encoding) -> Result<String> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Could not read the comment file {}", path.display()))?;
+    let text = text
+        .strip_suffix('\n')
+        .map(|t| t.strip_suffix('\r').unwrap_or(t))
+        .unwrap_or(&text);
+    let comment = truncate_encoded(text, encoding, COMMENT_SIZE);
+    if comment.len() < text.len() {
+        eprintln!(
+            "[WARN] The comment in {} is longer than {} bytes, only its first {} characters are kept.",
+            path.display(),
+            COMMENT_SIZE,
+            comment.chars().count()
+        );
+    }
+    Ok(comment.to_string())
+}
+
+/// `<name>.vdf` for a directory or the script `<name>.yml` or `<name>.json`.
+fn derived_archive_name(input: &Path) -> Result<String> {
+    let input = input.canonicalize()?;
+    let name = if input.is_dir() {
+        input.file_name()
     } else {
-        eprintln!("Please provide a yaml file or a base directory.");
-        exit(1);
+        input.file_stem()
+    };
+    match name {
+        Some(name) => Ok(format!("{}.vdf", name.to_string_lossy())),
+        None => bail!("Cannot derive an archive name from {}.", input.display()),
+    }
+}
+
+fn check_names(tree: &FileSystemNode, encoding: Encoding) -> Result<()> {
+    let problems = tree.name_problems(encoding);
+    for (path, problem) in &problems {
+        eprintln!("[WARN] {} {}", path, problem);
     }
 
+    if !problems.is_empty() {
+        bail!("Found {} problematic file names.", problems.len());
+    }
+    eprintln!("[INFO] All file names look fine.");
     Ok(())
 }
+
+/// Prints the progress of a build for `--progress`, at most once a second so large builds do not flood the terminal.
+struct ConsoleProgress {
+    // When the last line was printed, the files done and their bytes
+    state: Mutex<(Instant, u64, u64)>,
+}
+
+impl ConsoleProgress {
+    fn new() -> Self {
+        ConsoleProgress {
+            state: Mutex::new((Instant::now(), 0, 0)),
+        }
+    }
+
+    fn due(last: &mut Instant) -> bool {
+        if last.elapsed() < Duration::from_secs(1) {
+            return false;
+        }
+        *last = Instant::now();
+        true
+    }
+}
+
+impl ProgressSink for ConsoleProgress {
+    fn file_done(&self, _name: &str, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        let (last, files, total) = &mut *state;
+        *files += 1;
+        *total += bytes;
+        if Self::due(last) {
+            eprintln!("[INFO] {} files with {} bytes done", files, total);
+        }
+    }
+
+    fn bytes_written(&self, written: u64, total: u64) {
+        let mut state = self.state.lock().unwrap();
+        if Self::due(&mut state.0) || written == total {
+            eprintln!(
+                "[INFO] Written {} of {} bytes ({}%)",
+                written,
+                total,
+                written * 100 / total.max(1)
+            );
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use anyhow::Result;
+use chrono::{Datelike, Timelike};
+use std::path::{Path, PathBuf};
+
+use super::{filetree::FileSystemNode, to_dos_time, BuildOptions, Vdfs};
+
+/// Puts an archive together piece by piece, for when a single directory or script is not enough.
+///
+/// Paths inside the archive use `/` or `\` as separators, missing directories are created
+/// and an entry with the same name as an earlier one replaces it.
+#[derive(Debug)]
+pub struct VdfsBuilder {
+    options: BuildOptions,
+    root: FileSystemNode,
+    comment: Option<String>,
+    timestamp: Option<u32>,
+    // The first error of an `add_*` call, returned by `build`
+    error: Option<anyhow::Error>,
+}
+
+impl VdfsBuilder {
+    pub fn new(options: BuildOptions) -> Self {
+        VdfsBuilder {
+            options,
+            root: FileSystemNode::Directory {
+                name: String::new(),
+                path: PathBuf::new(),
+                children: Vec::new(),
+                level: -1,
+                is_last: false,
+            },
+            comment: None,
+            timestamp: None,
+            error: None,
+        }
+    }
+
+    /// Adds everything inside the directory `path` to the archive root,
+    /// directories that already exist in the archive are merged.
+    pub fn add_dir(mut self, path: &Path) -> Self {
+        let tree = Vdfs::tree_from_dir(&path.to_path_buf(), &mut self.options.tree_filter);
+        self.add_tree(tree);
+        self
+    }
+
+    /// Adds the files below `base_dir` that match any of `globs` to the archive root,
+    /// like the `file_include_globs` of a script. Fails on `build` if `base_dir` does not exist.
+    pub fn add_globs(mut self, base_dir: &Path, globs: &[&str]) -> Self {
+        match Vdfs::tree_from_globs(base_dir, globs, &mut self.options.tree_filter) {
+            Ok(tree) => self.add_tree(tree),
+            Err(e) => {
+                self.error.get_or_insert(e);
+            }
+        }
+        self
+    }
+
+    /// Adds the file at `path` on disk as `archive_path`, it is only read on `build`.
+    pub fn add_file(mut self, archive_path: &str, path: &Path) -> Self {
+        if let Some(node) = nest(archive_path, |name| FileSystemNode::File {
+            name,
+            path: path.to_path_buf(),
+            contents: None,
+            is_last: false,
+            level: 0,
+        }) {
+            self.insert(node);
+        }
+        self
+    }
+
+    /// Adds a file holding `data` as `archive_path`, for content that never exists on disk.
+    pub fn add_raw(mut self, archive_path: &str, data: Vec<u8>) -> Self {
+        if let Some(node) = nest(archive_path, |name| FileSystemNode::File {
+            path: PathBuf::from(&name),
+            name,
+            contents: Some(data),
+            is_last: false,
+            level: 0,
+        }) {
+            self.insert(node);
+        }
+        self
+    }
+
+    pub fn set_comment(mut self, comment: Option<&str>) -> Self {
+        self.comment = comment.map(str::to_string);
+        self
+    }
+
+    /// Uses `time` for the header instead of the moment the archive is built.
+    pub fn set_timestamp(mut self, time: &(impl Datelike + Timelike)) -> Self {
+        self.timestamp = Some(to_dos_time(time));
+        self
+    }
+
+    /// Reads the files and builds the archive. Fails with the first error of adding files,
+    /// when a file cannot be read, the build is cancelled or the archive outgrows the 4 GiB
+    /// its offsets can address.
+    pub fn build(self) -> Result<Vdfs> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        let vdfs = Vdfs::from_tree(self.root, &self.options)?;
+        let mut vdfs = vdfs.add_comment(self.comment.as_deref());
+        if let Some(timestamp) = self.timestamp {
+            vdfs.header.timestamp = timestamp;
+        }
+        Ok(vdfs)
+    }
+
+    fn add_tree(&mut self, tree: FileSystemNode) {
+        let FileSystemNode::Directory { children, .. } = tree else {
+            return;
+        };
+        // Nothing to merge with, keep the children as they are so their order survives
+        if let FileSystemNode::Directory {
+            children: root_children,
+            ..
+        } = &mut self.root
+        {
+            if root_children.is_empty() {
+                *root_children = children;
+                return;
+            }
+        }
+        for child in children {
+            self.insert(child);
+        }
+    }
+
+    fn insert(&mut self, node: FileSystemNode) {
+        for replaced in self.root.merge_child(node) {
+            eprintln!(
+                "[WARN] {} was replaced by a later entry with the same name.",
+                replaced.path().display()
+            );
+        }
+    }
+}
+
+/// Wraps the node `leaf` creates for the last component of `archive_path`
+/// in one directory per preceding component.
+pub(super) fn nest(
+    archive_path: &str,
+    leaf: impl FnOnce(String) -> FileSystemNode,
+) -> Option<FileSystemNode> {
+    let mut components: Vec<_> = archive_path
+        .split(['/', '\\'])
+        .filter(|c| !c.is_empty())
+        .collect();
+    let Some(name) = components.pop() else {
+        eprintln!(
+            "[WARN] {} is not a valid entry name, skipping it.",
+            archive_path
+        );
+        return None;
+    };
+
+    let mut node = leaf(name.to_string());
+    for (i, dir) in components.iter().enumerate().rev() {
+        // The only child of a fresh directory
+        match &mut node {
+            FileSystemNode::Directory { is_last, .. } | FileSystemNode::File { is_last, .. } => {
+                *is_last = true
+            }
+        }
+        node = FileSystemNode::Directory {
+            name: dir.to_string(),
+            path: components[..=i].iter().collect(),
+            children: vec![node],
+            level: 0,
+            is_last: false,
+        };
+    }
+    Some(node)
+}
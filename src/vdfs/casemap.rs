@@ -0,0 +1,51 @@
+use anyhow::{Context, Result};
+use std::{collections::HashMap, fs, path::Path};
+
+use super::Vdfs;
+
+// The catalog only stores uppercase names, the case map keeps the original spelling
+// next to the archive as UTF-8 text, one logical path with `/` separators per line.
+
+impl Vdfs {
+    /// Writes the original spelling of every entry path to `path`.
+    pub fn write_case_map(&self, path: &Path) -> Result<()> {
+        let mut map = self.entry_paths.join("\n");
+        map.push('\n');
+        fs::write(path, map).with_context(|| format!("Could not write {}", path.display()))?;
+        eprintln!("[INFO] Wrote the case map {}", path.display());
+        Ok(())
+    }
+
+    /// Renames entries to the spelling recorded in the case map at `path`,
+    /// entries the map does not mention keep their stored name.
+    pub fn apply_case_map(&mut self, path: &Path) -> Result<()> {
+        let map = fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let spellings: HashMap<String, &str> = map
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(|l| (l.to_ascii_uppercase(), l.rsplit('/').next().unwrap_or(l)))
+            .collect();
+
+        let mut restored = 0;
+        for (entry, entry_path) in self.catalog_dirs.iter_mut().zip(&self.entry_paths) {
+            if let Some(name) = spellings.get(&entry_path.to_ascii_uppercase()) {
+                entry.name_utf8 = name.to_string();
+                restored += 1;
+            }
+        }
+        if restored < self.catalog_dirs.len() {
+            eprintln!(
+                "[WARN] The case map {} has no spelling for {} entries.",
+                path.display(),
+                self.catalog_dirs.len() - restored
+            );
+        }
+
+        self.index_paths();
+        if self.opened {
+            self.restore_tree();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::{bail, Context, Result};
+use std::{fmt::Write, fs, path::Path};
+
+use super::Vdfs;
+
+// The checksum manifest lists the CRC32 of every file as eight lowercase hex digits,
+// two spaces and the logical path, one file per line in catalog order.
+
+impl Vdfs {
+    /// The CRC32 of every file by logical path, in catalog order. Taken from the attributes when
+    /// the archive was built with `crc_in_attributes`, otherwise the contents are read, from the
+    /// source files for a built archive.
+    pub fn checksums(&self) -> Result<Vec<(String, u32)>> {
+        let mut checksums = Vec::new();
+        for (path, entry) in self.entries() {
+            if entry.is_dir {
+                continue;
+            }
+            let crc = if self.crc_in_attributes && !self.opened {
+                entry.attributes
+            } else {
+                let Some(data) = self.entry_data(entry) else {
+                    bail!("The data of {} cannot be read.", path);
+                };
+                crc32fast::hash(&data)
+            };
+            checksums.push((path.to_string(), crc));
+        }
+        Ok(checksums)
+    }
+
+    /// Writes the manifest of `checksums` to `path`, two builds can be compared with a plain diff.
+    pub fn write_checksums(&self, path: &Path) -> Result<()> {
+        let mut manifest = String::new();
+        for (file, crc) in self.checksums()? {
+            writeln!(manifest, "{:08x}  {}", crc, file).unwrap();
+        }
+        fs::write(path, manifest).with_context(|| format!("Could not write {}", path.display()))?;
+        eprintln!("[INFO] Wrote the checksums {}", path.display());
+        Ok(())
+    }
+}
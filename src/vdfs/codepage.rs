@@ -0,0 +1,152 @@
+use anyhow::{bail, Result};
+use encoding_rs::SHIFT_JIS;
+
+/// What to do with bytes that have no character in the codepage.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DecodeFallback {
+    /// Replace them with U+FFFD
+    #[default]
+    Replace,
+    /// Fail with an error naming the offending byte
+    Error,
+    /// Pass them through as the Latin-1 character with the same value
+    Latin1,
+}
+
+/// Codepage of names and the comment. The engine just passes the bytes through,
+/// so which one applies depends on the language of the game the archive is made for.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Encoding {
+    /// Windows-1252, used by the western releases
+    #[default]
+    Cp1252,
+    /// Shift-JIS, used by Japanese mods
+    ShiftJis,
+    /// ISO-8859-1, every byte is the character with the same value
+    Latin1,
+}
+
+/// Windows-1252 characters for 0x80..=0x9F, `None` marks the five unassigned bytes.
+/// Everything else maps to the Unicode code point with the same value.
+const CP1252_HIGH: [Option<char>; 32] = [
+    Some('\u{20AC}'),
+    None,
+    Some('\u{201A}'),
+    Some('\u{0192}'),
+    Some('\u{201E}'),
+    Some('\u{2026}'),
+    Some('\u{2020}'),
+    Some('\u{2021}'),
+    Some('\u{02C6}'),
+    Some('\u{2030}'),
+    Some('\u{0160}'),
+    Some('\u{2039}'),
+    Some('\u{0152}'),
+    None,
+    Some('\u{017D}'),
+    None,
+    None,
+    Some('\u{2018}'),
+    Some('\u{2019}'),
+    Some('\u{201C}'),
+    Some('\u{201D}'),
+    Some('\u{2022}'),
+    Some('\u{2013}'),
+    Some('\u{2014}'),
+    Some('\u{02DC}'),
+    Some('\u{2122}'),
+    Some('\u{0161}'),
+    Some('\u{203A}'),
+    Some('\u{0153}'),
+    None,
+    Some('\u{017E}'),
+    Some('\u{0178}'),
+];
+
+pub fn decode_cp1252(bytes: &[u8], fallback: DecodeFallback) -> Result<String> {
+    let mut s = String::with_capacity(bytes.len());
+    for (i, &b) in bytes.iter().enumerate() {
+        let c = match b {
+            0x80..=0x9F => CP1252_HIGH[(b - 0x80) as usize],
+            _ => Some(b as char),
+        };
+        match (c, fallback) {
+            (Some(c), _) => s.push(c),
+            (None, DecodeFallback::Replace) => s.push(char::REPLACEMENT_CHARACTER),
+            (None, DecodeFallback::Latin1) => s.push(b as char),
+            (None, DecodeFallback::Error) => {
+                bail!(
+                    "Byte 0x{:02X} at offset {} is not valid Windows-1252.",
+                    b,
+                    i
+                )
+            }
+        }
+    }
+    Ok(s)
+}
+
+/// The Windows-1252 byte for `c`, if there is one.
+pub fn encode_cp1252_char(c: char) -> Option<u8> {
+    match c as u32 {
+        0x00..=0x7F | 0xA0..=0xFF => Some(c as u8),
+        _ => CP1252_HIGH
+            .iter()
+            .position(|&h| h == Some(c))
+            .map(|i| 0x80 + i as u8),
+    }
+}
+
+/// Decodes `bytes` in `encoding`, undecodable bytes are handled according to `fallback`.
+pub fn decode(bytes: &[u8], encoding: Encoding, fallback: DecodeFallback) -> Result<String> {
+    match encoding {
+        Encoding::Cp1252 => decode_cp1252(bytes, fallback),
+        Encoding::Latin1 => Ok(bytes.iter().map(|&b| b as char).collect()),
+        Encoding::ShiftJis => {
+            let (text, had_errors) = SHIFT_JIS.decode_without_bom_handling(bytes);
+            if had_errors && fallback == DecodeFallback::Error {
+                bail!("{:02X?} is not valid Shift-JIS.", bytes);
+            }
+            Ok(text.into_owned())
+        }
+    }
+}
+
+/// Encodes `text` in `encoding`, characters it cannot represent become `?`.
+/// The second value tells whether that happened.
+pub fn encode(text: &str, encoding: Encoding) -> (Vec<u8>, bool) {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut lossy = false;
+    for c in text.chars() {
+        let encoded = match encoding {
+            Encoding::Cp1252 => encode_cp1252_char(c).map(|b| vec![b]),
+            Encoding::Latin1 => u8::try_from(c as u32).ok().map(|b| vec![b]),
+            Encoding::ShiftJis => {
+                let mut buf = [0; 4];
+                let (b, _, had_errors) = SHIFT_JIS.encode(c.encode_utf8(&mut buf));
+                (!had_errors).then(|| b.into_owned())
+            }
+        };
+        match encoded {
+            Some(b) => bytes.extend(b),
+            None => {
+                bytes.push(b'?');
+                lossy = true;
+            }
+        }
+    }
+    (bytes, lossy)
+}
+
+/// The longest prefix of `text` that takes at most `max_bytes` once encoded in `encoding`,
+/// it always ends on a character boundary.
+pub fn truncate_encoded(text: &str, encoding: Encoding, max_bytes: usize) -> &str {
+    let mut len = 0;
+    for (i, c) in text.char_indices() {
+        len += encode(c.encode_utf8(&mut [0; 4]), encoding).0.len();
+        if len > max_bytes {
+            return &text[..i];
+        }
+    }
+    text
+}
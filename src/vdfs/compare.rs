@@ -0,0 +1,189 @@
+use anyhow::{Context, Result};
+use core::fmt;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::Vdfs;
+
+/// The differences `Vdfs::compare_to_dir` found, every list holds logical paths with `/` separators.
+#[derive(Debug, Default, Clone)]
+pub struct ComparisonReport {
+    /// Files of the archive the directory does not have
+    pub missing: Vec<String>,
+    /// Files of the directory the archive does not have
+    pub extra: Vec<String>,
+    /// Files both have, but with a different size or, when contents are compared, different bytes
+    pub differing: Vec<String>,
+    /// Files found in both
+    pub matching: usize,
+}
+
+impl ComparisonReport {
+    pub fn is_identical(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.differing.is_empty()
+    }
+}
+
+impl fmt::Display for ComparisonReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for path in &self.missing {
+            writeln!(f, "missing:   {}", path)?;
+        }
+        for path in &self.extra {
+            writeln!(f, "extra:     {}", path)?;
+        }
+        for path in &self.differing {
+            writeln!(f, "differing: {}", path)?;
+        }
+        write!(
+            f,
+            "{} matching, {} missing, {} extra, {} differing",
+            self.matching,
+            self.missing.len(),
+            self.extra.len(),
+            self.differing.len()
+        )
+    }
+}
+
+/// The differences `Vdfs::diff` found between two archives, every list holds logical paths
+/// with `/` separators sorted alphabetically.
+#[derive(Debug, Default, Clone)]
+pub struct ArchiveDiff {
+    /// Files only the newer archive has
+    pub added: Vec<String>,
+    /// Files only the older archive has
+    pub removed: Vec<String>,
+    /// Files both have, but with a different size or different bytes
+    pub changed: Vec<String>,
+    /// Files found unchanged in both
+    pub unchanged: usize,
+}
+
+impl ArchiveDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl fmt::Display for ArchiveDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for path in &self.added {
+            writeln!(f, "added:   {}", path)?;
+        }
+        for path in &self.removed {
+            writeln!(f, "removed: {}", path)?;
+        }
+        for path in &self.changed {
+            writeln!(f, "changed: {}", path)?;
+        }
+        write!(
+            f,
+            "{} added, {} removed, {} changed, {} unchanged",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len(),
+            self.unchanged
+        )
+    }
+}
+
+impl Vdfs {
+    /// Compares the files of this archive to those of `newer`, matching paths ignoring case like
+    /// the catalog does. Files are compared by size and then byte by byte, only by size when
+    /// the data of one of them is cut off. Directories only count through their files and
+    /// paths are spelled like in `newer`, or like in this archive for removed files.
+    pub fn diff(&self, newer: &Vdfs) -> ArchiveDiff {
+        let files = |vdfs: &Vdfs| -> BTreeMap<String, (String, usize)> {
+            vdfs.entries()
+                .enumerate()
+                .filter(|(_, (_, entry))| !entry.is_dir)
+                .map(|(i, (path, _))| (path.to_uppercase(), (path.to_string(), i)))
+                .collect()
+        };
+        let mut old = files(self);
+
+        let mut diff = ArchiveDiff::default();
+        for (key, (path, i)) in files(newer) {
+            let Some((_, j)) = old.remove(&key) else {
+                diff.added.push(path);
+                continue;
+            };
+            let (entry, old_entry) = (&newer.catalog_dirs[i], &self.catalog_dirs[j]);
+            let same = entry.size == old_entry.size
+                && match (newer.entry_data(entry), self.entry_data(old_entry)) {
+                    (Some(data), Some(old_data)) => data == old_data,
+                    _ => true,
+                };
+            if same {
+                diff.unchanged += 1;
+            } else {
+                diff.changed.push(path);
+            }
+        }
+        diff.removed = old.into_values().map(|(path, _)| path).collect();
+        diff
+    }
+
+    /// Compares every file of the archive to the file at the same path below `dir`, ignoring case
+    /// like the catalog does. Files are compared by size, and byte by byte as well with `contents`.
+    /// Directories only count through their files, and paths changed while packing, like with
+    /// `--path-rewrite` or `--flatten`, show up as missing and extra.
+    pub fn compare_to_dir(&self, dir: &Path, contents: bool) -> Result<ComparisonReport> {
+        let mut on_disk = BTreeMap::new();
+        collect_files(dir, "", &mut on_disk)?;
+
+        let mut report = ComparisonReport::default();
+        for (path, entry) in self.entries() {
+            if entry.is_dir {
+                continue;
+            }
+            let Some((_, source)) = on_disk.remove(&path.to_uppercase()) else {
+                report.missing.push(path.to_string());
+                continue;
+            };
+            let size = fs::metadata(&source)
+                .with_context(|| format!("Could not read {}", source.display()))?
+                .len();
+            let same = size == entry.size as u64
+                && (!contents
+                    || self
+                        .entry_data(entry)
+                        .is_some_and(|data| fs::read(&source).is_ok_and(|bytes| bytes == *data)));
+            if same {
+                report.matching += 1;
+            } else {
+                report.differing.push(path.to_string());
+            }
+        }
+        report.extra = on_disk.into_values().map(|(path, _)| path).collect();
+        Ok(report)
+    }
+}
+
+/// Every file below `dir` by uppercased logical path, with its spelled out path and location on disk.
+fn collect_files(
+    dir: &Path,
+    prefix: &str,
+    files: &mut BTreeMap<String, (String, PathBuf)>,
+) -> Result<()> {
+    let entries = fs::read_dir(dir).with_context(|| format!("Could not read {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Could not read {}", dir.display()))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name == "." || name == ".." {
+            continue;
+        }
+        let path = format!("{}{}", prefix, name);
+        let entry_path = entry.path();
+        if entry_path.is_dir() {
+            collect_files(&entry_path, &format!("{}/", path), files)?;
+        } else {
+            files.insert(path.to_uppercase(), (path, entry_path));
+        }
+    }
+    Ok(())
+}
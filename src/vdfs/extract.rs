@@ -0,0 +1,290 @@
+use anyhow::{bail, Context, Result};
+use chrono::{Local, TimeZone};
+use filetime::FileTime;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use super::{dos_time_to_datetime, Vdfs, ATTRIBUTE_READONLY};
+
+/// Settings for writing the entries of an archive to disk.
+#[derive(Debug, Default, Clone)]
+pub struct ExtractOptions {
+    /// Skip files whose data is cut off instead of failing on them
+    pub skip_truncated: bool,
+    /// Give every file the modification time stored in the header instead of the current time
+    pub restore_mtime: bool,
+    /// Make files whose entry has `ATTRIBUTE_READONLY` read-only, on Unix by removing the write permissions
+    pub restore_attributes: bool,
+    /// Write every file directly into the output directory, leaving out all directories
+    pub flatten: bool,
+    /// What happens when flattening puts two files with the same name into the output directory
+    pub on_collision: FlattenCollision,
+}
+
+/// How `ExtractOptions::flatten` deals with files of different directories sharing a name.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FlattenCollision {
+    /// Fail before anything is extracted, listing every clash
+    #[default]
+    Error,
+    /// Append _1, _2, ... to the name of every later file, before the extension
+    Number,
+}
+
+impl Vdfs {
+    /// Rebuilds the directories and files of the archive below `out_dir` with the default
+    /// `ExtractOptions`, failing before anything is written when the data of a file is cut off.
+    pub fn extract(&self, out_dir: &Path) -> Result<()> {
+        self.extract_with(out_dir, &ExtractOptions::default())
+    }
+
+    /// Former name of `extract`.
+    #[deprecated(note = "use `Vdfs::extract`")]
+    pub fn extract_to(&self, output_dir: &Path) -> Result<()> {
+        self.extract(output_dir)
+    }
+
+    /// Like `extract`, but skips files whose data is cut off instead of failing on them.
+    pub fn recover_to(&self, output_dir: &Path) -> Result<()> {
+        let options = ExtractOptions {
+            skip_truncated: true,
+            ..Default::default()
+        };
+        self.extract_with(output_dir, &options)
+    }
+
+    /// A reader over the contents of the file at the logical path `path`, `None` for
+    /// directories, unknown paths and files whose data is cut off. Every call gets its own
+    /// position, so threads sharing the archive can read the same entry at the same time.
+    /// Files of a built archive are read from their source into memory first.
+    pub fn open_entry(&self, path: &str) -> Option<Cursor<Cow<'_, [u8]>>> {
+        let entry = &self.catalog_dirs[self.find_entry(path)?];
+        if entry.is_dir {
+            return None;
+        }
+        self.entry_data(entry).map(Cursor::new)
+    }
+
+    /// The contents of the file at the logical path `path`, borrowed from the archive without
+    /// copying or extracting anything. The path is matched like `open_entry` and gives `None`
+    /// in the same cases, as well as for files of a built archive that are only read from
+    /// their source while writing.
+    pub fn read_entry(&self, path: &str) -> Option<&[u8]> {
+        let entry = &self.catalog_dirs[self.find_entry(path)?];
+        if entry.is_dir {
+            return None;
+        }
+        self.entry_slice(entry)
+    }
+
+    /// Writes the contents of the file at the logical path `path` to `target`.
+    pub fn extract_file(&self, path: &str, target: &Path) -> Result<()> {
+        let Some(index) = self.find_entry(path) else {
+            bail!("{} is not in the archive.", path);
+        };
+        let entry = &self.catalog_dirs[index];
+        if entry.is_dir {
+            bail!("{} is a directory.", path);
+        }
+        let Some(data) = self.entry_data(entry) else {
+            bail!("The data of {} lies outside of the archive.", path);
+        };
+        fs::write(target, data).with_context(|| format!("Could not write {}", target.display()))
+    }
+
+    /// How many bytes extracting every file writes, summed as u64 so archives
+    /// close to the 4 GiB limit do not overflow. Files sharing data count once each.
+    pub fn total_extracted_size(&self) -> u64 {
+        self.catalog_dirs
+            .iter()
+            .filter(|e| !e.is_dir)
+            .map(|e| e.size as u64)
+            .sum()
+    }
+
+    /// Calls `f` with the logical path and the contents of every file in catalog order,
+    /// stops at the first error `f` returns.
+    pub fn for_each_file(&self, mut f: impl FnMut(&str, &[u8]) -> Result<()>) -> Result<()> {
+        for (path, entry) in self.entries() {
+            if entry.is_dir {
+                continue;
+            }
+            let Some(data) = self.entry_data(entry) else {
+                bail!("The data of {} lies outside of the archive.", path);
+            };
+            f(path, &data)?;
+        }
+        Ok(())
+    }
+
+    pub fn extract_with(&self, output_dir: &Path, options: &ExtractOptions) -> Result<()> {
+        if self.catalog_dirs.is_empty() {
+            eprintln!("[INFO] The archive is empty, nothing to extract.");
+            return Ok(());
+        }
+        // Checked up front so a corrupt archive does not leave half of it extracted
+        let truncated = self.truncated_entries();
+        if !truncated.is_empty() && !options.skip_truncated {
+            bail!(
+                "The data of {} entries lies outside of the archive, nothing was extracted: {}",
+                truncated.len(),
+                truncated.join(", ")
+            );
+        }
+
+        let flat_names = if options.flatten {
+            Some(self.flat_names(options.on_collision)?)
+        } else {
+            None
+        };
+
+        let mtime = if options.restore_mtime {
+            self.header_mtime()
+        } else {
+            None
+        };
+
+        eprintln!("[INFO] Extracting into {}", output_dir.display());
+        fs::create_dir_all(output_dir)
+            .with_context(|| format!("Could not create {}", output_dir.display()))?;
+
+        let mut skipped = 0;
+        let mut extracted = 0;
+        for (path, entry) in self.entries() {
+            let target = match &flat_names {
+                Some(_) if entry.is_dir => continue,
+                Some(names) => target_path(output_dir, &names[path])?,
+                None => target_path(output_dir, path)?,
+            };
+            extracted += 1;
+            if entry.is_dir {
+                fs::create_dir_all(&target)
+                    .with_context(|| format!("Could not create {}", target.display()))?;
+                continue;
+            }
+
+            let Some(data) = self.entry_data(entry) else {
+                if options.skip_truncated {
+                    eprintln!("[WARN] Skipping {}, its data is truncated.", path);
+                    skipped += 1;
+                    continue;
+                }
+                bail!("The data of {} lies outside of the archive.", path);
+            };
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("Could not create {}", parent.display()))?;
+            }
+            fs::write(&target, data)
+                .with_context(|| format!("Could not write {}", target.display()))?;
+            if let Some(mtime) = mtime {
+                filetime::set_file_mtime(&target, mtime).with_context(|| {
+                    format!(
+                        "Could not set the modification time of {}",
+                        target.display()
+                    )
+                })?;
+            }
+            if options.restore_attributes && entry.attributes & ATTRIBUTE_READONLY != 0 {
+                let mut permissions = fs::metadata(&target)
+                    .with_context(|| format!("Could not read {}", target.display()))?
+                    .permissions();
+                permissions.set_readonly(true);
+                fs::set_permissions(&target, permissions)
+                    .with_context(|| format!("Could not make {} read-only", target.display()))?;
+            }
+        }
+
+        eprintln!("[INFO] Extracted {} entries.", extracted - skipped);
+        Ok(())
+    }
+}
+
+impl Vdfs {
+    /// The name every file gets when the directories are left out, by logical path.
+    fn flat_names(&self, on_collision: FlattenCollision) -> Result<HashMap<&str, String>> {
+        let files: Vec<&str> = self
+            .entries()
+            .filter(|(_, entry)| !entry.is_dir)
+            .map(|(path, _)| path)
+            .collect();
+        let basename = |path: &str| path.rsplit('/').next().unwrap_or(path).to_string();
+
+        // Claimed up front so a numbered name never takes the name of a later file
+        let mut taken: HashMap<String, Vec<&str>> = HashMap::new();
+        for &path in &files {
+            taken
+                .entry(basename(path).to_ascii_uppercase())
+                .or_default()
+                .push(path);
+        }
+        let mut clashes: Vec<_> = taken.values().filter(|paths| paths.len() > 1).collect();
+        if clashes.is_empty() {
+            return Ok(files.into_iter().map(|p| (p, basename(p))).collect());
+        }
+        if on_collision == FlattenCollision::Error {
+            clashes.sort();
+            let clashes: Vec<String> = clashes.iter().map(|paths| paths.join(" and ")).collect();
+            bail!(
+                "{} names are used by more than one file, nothing was extracted: {}",
+                clashes.len(),
+                clashes.join(", ")
+            );
+        }
+
+        let mut used: HashSet<String> = taken.keys().cloned().collect();
+        let mut names = HashMap::new();
+        for path in files {
+            let name = basename(path);
+            if taken[&name.to_ascii_uppercase()][0] == path {
+                names.insert(path, name);
+                continue;
+            }
+            let (stem, extension) = match name.rsplit_once('.') {
+                Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+                _ => (name.as_str(), String::new()),
+            };
+            let numbered = (1..)
+                .map(|i| format!("{}_{}{}", stem, i, extension))
+                .find(|n| !used.contains(&n.to_ascii_uppercase()))
+                .unwrap();
+            eprintln!("[INFO] Extracting {} as {}", path, numbered);
+            used.insert(numbered.to_ascii_uppercase());
+            names.insert(path, numbered);
+        }
+        Ok(names)
+    }
+
+    /// The header timestamp as a file time, it is read as local time like the original tools wrote it.
+    fn header_mtime(&self) -> Option<FileTime> {
+        let time = dos_time_to_datetime(self.header.timestamp)
+            .and_then(|t| Local.from_local_datetime(&t).earliest());
+        if time.is_none() {
+            eprintln!(
+                "[WARN] The archive has no valid timestamp, the extracted files keep the current time."
+            );
+        }
+        time.map(|t| FileTime::from_unix_time(t.timestamp(), 0))
+    }
+}
+
+/// Where the entry at the logical path `path` ends up, refusing names that would escape `output_dir`.
+fn target_path(output_dir: &Path, path: &str) -> Result<PathBuf> {
+    let mut target = output_dir.to_path_buf();
+    for component in path.split('/') {
+        if component.is_empty()
+            || component == "."
+            || component == ".."
+            || component.contains(['\\', ':'])
+        {
+            bail!("{} is not a safe path to extract to.", path);
+        }
+        target.push(component);
+    }
+    Ok(target)
+}
@@ -1,8 +1,25 @@
-use std::{path::PathBuf, process::exit, io, borrow::Cow};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    fs::{self, DirEntry},
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
 
-use ptree::{TreeItem, Style};
+use ignore::{gitignore::Gitignore, Match};
+use ptree::{Style, TreeItem};
 
-use super::is_on_level;
+use super::{
+    codepage::{encode, Encoding},
+    encode_entry_name, is_on_level,
+};
+
+/// Directories with more entries than this always go through the general walk
+const SMALL_DIR_ENTRIES: usize = 256;
+
+/// Characters Windows does not allow in file names
+const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum FileSystemNode {
@@ -17,36 +34,24 @@ pub enum FileSystemNode {
     File {
         name: String,
         path: PathBuf,
+        // Generated data that is packed instead of reading `path`
+        contents: Option<Vec<u8>>,
 
         is_last: bool,
         level: i32,
     },
 }
 
-trait Name {
-    fn name(&self) -> &str;
-}
-
-impl Name for FileSystemNode {
-    fn name(&self) -> &str {
-        match self {
-            FileSystemNode::Directory { name, .. } => name,
-            FileSystemNode::File { name, .. } => name,
-        }
-    }
-}
-
 impl TreeItem for FileSystemNode {
     type Child = Self;
     fn write_self<W: io::Write>(&self, f: &mut W, style: &Style) -> io::Result<()> {
         write!(f, "{}", style.paint(self.name()))
     }
-    fn children(&self) -> Cow<[Self::Child]> {
+    fn children(&self) -> Cow<'_, [Self::Child]> {
         match self {
             FileSystemNode::Directory { children, .. } => Cow::from(children),
-            FileSystemNode::File { .. } =>  Cow::from(vec![]),
+            FileSystemNode::File { .. } => Cow::from(vec![]),
         }
-
     }
 }
 
@@ -66,34 +71,831 @@ impl FileSystemNode {
             | (
                 FileSystemNode::File { name: name_a, .. },
                 FileSystemNode::File { name: name_b, .. },
-            ) => name_a.to_uppercase().cmp(&name_b.to_uppercase()),
+            ) => name_a
+                .to_ascii_uppercase()
+                .cmp(&name_b.to_ascii_uppercase()),
+        }
+    }
+
+    /// Lists the logical path of every node below this one and whether it is a directory,
+    /// in the same (BFS) order `build_catalog` emits them.
+    pub fn flatten(&self) -> Vec<(String, bool)> {
+        let mut paths = Vec::new();
+        let mut queue = VecDeque::new();
+        queue.push_back((String::new(), self));
+
+        while let Some((prefix, node)) = queue.pop_front() {
+            if let FileSystemNode::Directory { children, .. } = node {
+                for child in children {
+                    let path = if prefix.is_empty() {
+                        child.name().to_string()
+                    } else {
+                        format!("{}/{}", prefix, child.name())
+                    };
+                    let is_dir = child.is_dir();
+                    paths.push((path.clone(), is_dir));
+                    queue.push_back((path, child));
+                }
+            }
+        }
+
+        paths
+    }
+
+    /// Lists entry names the engine is known to have trouble with once stored in `encoding`,
+    /// as pairs of logical path and what is wrong with the name.
+    pub fn name_problems(&self, encoding: Encoding) -> Vec<(String, &'static str)> {
+        let mut problems = Vec::new();
+        for (path, _) in self.flatten() {
+            let name = path.rsplit('/').next().unwrap_or_default();
+            if name.contains(' ') {
+                problems.push((path.clone(), "contains spaces"));
+            }
+            if name
+                .chars()
+                .any(|c| c.is_control() || RESERVED_CHARS.contains(&c))
+            {
+                problems.push((path.clone(), "contains control or reserved characters"));
+            }
+            let (bytes, lossy) = encode(name, encoding);
+            if lossy {
+                problems.push((path.clone(), "contains characters outside of the codepage"));
+            }
+            if bytes.len() > 64 {
+                problems.push((path.clone(), "is longer than 64 bytes"));
+            }
+        }
+        problems
+    }
+
+    /// Moves every file to the logical path `rewrite` maps its current one to, directories are
+    /// created as the new paths need them and ones left without files disappear.
+    /// Fails naming both source paths when two files would end up at the same path.
+    pub fn rewrite_paths(&mut self, rewrite: impl Fn(&str) -> String) -> anyhow::Result<()> {
+        let mut files = Vec::new();
+        self.take_files("", &mut files);
+
+        let mut targets: HashMap<String, PathBuf> = HashMap::new();
+        for (path, mut file) in files {
+            let new_path = rewrite(&path);
+            let mut components: Vec<_> = new_path
+                .split(['/', '\\'])
+                .filter(|c| !c.is_empty())
+                .collect();
+            let Some(new_name) = components.pop() else {
+                anyhow::bail!("{} is rewritten to an empty path.", file.path().display());
+            };
+            let key = new_path.to_ascii_uppercase();
+            if let Some(other) = targets.insert(key, file.path().clone()) {
+                anyhow::bail!(
+                    "{} and {} are both rewritten to {}.",
+                    other.display(),
+                    file.path().display(),
+                    new_path
+                );
+            }
+
+            if let FileSystemNode::File { name, .. } = &mut file {
+                *name = new_name.to_string();
+            }
+            let dirs: Vec<(String, PathBuf)> = (0..components.len())
+                .map(|i| (components[i].to_string(), components[..=i].iter().collect()))
+                .collect();
+            self.insert_at(&dirs, file);
+        }
+        Ok(())
+    }
+
+    /// Removes every file below this directory, together with its logical path below `prefix`.
+    pub(super) fn take_files(&mut self, prefix: &str, files: &mut Vec<(String, FileSystemNode)>) {
+        let FileSystemNode::Directory { children, .. } = self else {
+            return;
+        };
+        for mut child in children.drain(..) {
+            let path = if prefix.is_empty() {
+                child.name().to_string()
+            } else {
+                format!("{}/{}", prefix, child.name())
+            };
+            match child {
+                FileSystemNode::Directory { .. } => child.take_files(&path, files),
+                FileSystemNode::File { .. } => files.push((path, child)),
+            }
+        }
+    }
+
+    /// Moves every file below this directory directly into it and drops all subdirectories.
+    /// Fails naming both source paths when two files would end up with the same name.
+    pub fn flatten_into_root(&mut self) -> anyhow::Result<()> {
+        let FileSystemNode::Directory {
+            children, level, ..
+        } = self
+        else {
+            return Ok(());
+        };
+
+        let mut files: Vec<FileSystemNode> = Vec::new();
+        let mut queue: VecDeque<FileSystemNode> = children.drain(..).collect();
+        while let Some(node) = queue.pop_front() {
+            match node {
+                FileSystemNode::Directory { children, .. } => queue.extend(children),
+                FileSystemNode::File { .. } => {
+                    if let Some(existing) = files
+                        .iter()
+                        .find(|f| f.name().eq_ignore_ascii_case(node.name()))
+                    {
+                        anyhow::bail!(
+                            "{} and {} have the same name, they cannot both be flattened into the archive root.",
+                            existing.path().display(),
+                            node.path().display()
+                        );
+                    }
+                    files.push(node);
+                }
+            }
+        }
+
+        for file in files.iter_mut() {
+            if let FileSystemNode::File { level: lvl, .. } = file {
+                *lvl = *level + 1;
+            }
+        }
+        files.sort_by(FileSystemNode::cmp_file_system_nodes);
+        let last = files.len().saturating_sub(1);
+        for (i, file) in files.iter_mut().enumerate() {
+            if let FileSystemNode::File { is_last, .. } = file {
+                *is_last = i == last;
+            }
+        }
+        *children = files;
+
+        Ok(())
+    }
+
+    /// Sorts the children of every directory by the name as it is stored in the catalog, byte
+    /// by byte like the reference packers, and marks the last one of each directory.
+    /// With `dirs_first` subdirectories come before files, otherwise loaders can binary-search
+    /// each directory.
+    pub fn sort_by_stored_name(&mut self, encoding: Encoding, dirs_first: bool) {
+        self.sort_children_by_stored_name(encoding, dirs_first);
+        if let FileSystemNode::Directory { children, .. } = self {
+            for c in children {
+                c.sort_by_stored_name(encoding, dirs_first);
+            }
         }
     }
+
+    /// Like `sort_by_stored_name`, but leaves the directories below this one as they are.
+    pub fn sort_children_by_stored_name(&mut self, encoding: Encoding, dirs_first: bool) {
+        let FileSystemNode::Directory { children, .. } = self else {
+            return;
+        };
+
+        children.sort_by_cached_key(|c| {
+            (
+                dirs_first && !c.is_dir(),
+                encode_entry_name(c.name(), encoding),
+            )
+        });
+        let last = children.len().saturating_sub(1);
+        for (i, c) in children.iter_mut().enumerate() {
+            match c {
+                FileSystemNode::Directory { is_last, .. }
+                | FileSystemNode::File { is_last, .. } => *is_last = i == last,
+            }
+        }
+    }
+
+    /// The name the node gets in the archive.
+    pub fn name(&self) -> &str {
+        match self {
+            FileSystemNode::Directory { name, .. } => name,
+            FileSystemNode::File { name, .. } => name,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        matches!(self, FileSystemNode::Directory { .. })
+    }
+
+    /// How deep the node is in the tree, -1 for the root and 0 for its children.
+    pub fn level(&self) -> i32 {
+        match self {
+            FileSystemNode::Directory { level, .. } | FileSystemNode::File { level, .. } => *level,
+        }
+    }
+
+    /// The entries of a directory, empty for files.
+    pub fn children(&self) -> &[FileSystemNode] {
+        match self {
+            FileSystemNode::Directory { children, .. } => children,
+            FileSystemNode::File { .. } => &[],
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        match self {
+            FileSystemNode::Directory { path, .. } | FileSystemNode::File { path, .. } => path,
+        }
+    }
+
+    /// Removes every file below this directory `keep` rejects, given its source path,
+    /// and the directories left without entries by that. Returns how many files were removed.
+    pub fn retain_files(&mut self, keep: &impl Fn(&Path) -> bool) -> usize {
+        let FileSystemNode::Directory { children, .. } = self else {
+            return 0;
+        };
+        let mut removed = 0;
+        children.retain_mut(|child| match child {
+            FileSystemNode::File { path, .. } => {
+                let kept = keep(path);
+                removed += usize::from(!kept);
+                kept
+            }
+            FileSystemNode::Directory {
+                children: grandchildren,
+                ..
+            } => {
+                let was_empty = grandchildren.is_empty();
+                removed += child.retain_files(keep);
+                was_empty || !matches!(child, FileSystemNode::Directory { children, .. } if children.is_empty())
+            }
+        });
+        let last = children.len().saturating_sub(1);
+        for (i, c) in children.iter_mut().enumerate() {
+            match c {
+                FileSystemNode::Directory { is_last, .. }
+                | FileSystemNode::File { is_last, .. } => *is_last = i == last,
+            }
+        }
+        removed
+    }
+
+    /// Orders the children of every directory below this one, at the logical path `path`,
+    /// by the rank `ranks` gives their logical path. A directory ranks like the first listed
+    /// entry below it, unlisted entries keep their order after the listed ones.
+    /// Returns the rank of this node.
+    pub fn sort_by_rank(&mut self, path: &str, ranks: &HashMap<String, usize>) -> Option<usize> {
+        let own = ranks.get(&path.to_ascii_uppercase()).copied();
+        let FileSystemNode::Directory { children, .. } = self else {
+            return own;
+        };
+        let mut ranked: Vec<_> = children
+            .drain(..)
+            .map(|mut child| {
+                let child_path = if path.is_empty() {
+                    child.name().to_string()
+                } else {
+                    format!("{}/{}", path, child.name())
+                };
+                (child.sort_by_rank(&child_path, ranks), child)
+            })
+            .collect();
+        ranked.sort_by_key(|(rank, _)| rank.unwrap_or(usize::MAX));
+
+        let first = ranked.iter().filter_map(|(rank, _)| *rank).min();
+        *children = ranked.into_iter().map(|(_, child)| child).collect();
+        let last = children.len().saturating_sub(1);
+        for (i, c) in children.iter_mut().enumerate() {
+            match c {
+                FileSystemNode::Directory { is_last, .. }
+                | FileSystemNode::File { is_last, .. } => *is_last = i == last,
+            }
+        }
+        own.into_iter().chain(first).min()
+    }
+
+    /// Moves all children of this directory into a new directory `name`, which becomes its only child.
+    pub fn wrap_children(&mut self, name: &str) {
+        let FileSystemNode::Directory {
+            children, level, ..
+        } = self
+        else {
+            return;
+        };
+        let mut wrapper = FileSystemNode::Directory {
+            name: name.to_string(),
+            path: PathBuf::from(name),
+            children: std::mem::take(children),
+            level: *level + 1,
+            is_last: true,
+        };
+        wrapper.shift_levels(1);
+        children.push(wrapper);
+    }
+
+    /// Adds `by` to the level of every node below this one.
+    fn shift_levels(&mut self, by: i32) {
+        if let FileSystemNode::Directory { children, .. } = self {
+            for child in children {
+                match child {
+                    FileSystemNode::Directory { level, .. }
+                    | FileSystemNode::File { level, .. } => *level += by,
+                }
+                child.shift_levels(by);
+            }
+        }
+    }
+
+    /// Removes every directory below this one that has no files anywhere below it,
+    /// returns how many directories were removed.
+    pub fn drop_empty_dirs(&mut self) -> usize {
+        let FileSystemNode::Directory { children, .. } = self else {
+            return 0;
+        };
+        let mut dropped = 0;
+        children.retain_mut(|child| {
+            dropped += child.drop_empty_dirs();
+            let empty =
+                matches!(child, FileSystemNode::Directory { children, .. } if children.is_empty());
+            dropped += usize::from(empty);
+            !empty
+        });
+        if let Some(last) = children.last_mut() {
+            match last {
+                FileSystemNode::Directory { is_last, .. }
+                | FileSystemNode::File { is_last, .. } => *is_last = true,
+            }
+        }
+        dropped
+    }
+
+    /// Moves the entry at `path`, given relative to this directory, to the start or the end
+    /// of the directory it is in. Returns whether there is such an entry.
+    pub fn move_child(&mut self, path: &[&str], to_front: bool) -> bool {
+        let FileSystemNode::Directory { children, .. } = self else {
+            return false;
+        };
+        let Some((name, rest)) = path.split_first() else {
+            return false;
+        };
+        let Some(i) = children
+            .iter()
+            .position(|c| c.name().eq_ignore_ascii_case(name))
+        else {
+            return false;
+        };
+        if !rest.is_empty() {
+            return children[i].move_child(rest, to_front);
+        }
+
+        let child = children.remove(i);
+        if to_front {
+            children.insert(0, child);
+        } else {
+            children.push(child);
+        }
+        let last = children.len() - 1;
+        for (i, c) in children.iter_mut().enumerate() {
+            match c {
+                FileSystemNode::Directory { is_last, .. }
+                | FileSystemNode::File { is_last, .. } => *is_last = i == last,
+            }
+        }
+        true
+    }
+
+    /// Inserts `child` into this directory keeping the children sorted,
+    /// returns the node it replaced if one with the same name already existed.
+    pub fn add_child(&mut self, mut child: FileSystemNode) -> Option<FileSystemNode> {
+        let FileSystemNode::Directory {
+            children, level, ..
+        } = self
+        else {
+            return None;
+        };
+
+        match &mut child {
+            FileSystemNode::Directory { level: lvl, .. }
+            | FileSystemNode::File { level: lvl, .. } => *lvl = *level + 1,
+        }
+
+        let replaced = children
+            .iter()
+            .position(|c| c.name().eq_ignore_ascii_case(child.name()))
+            .map(|i| children.remove(i));
+        children.push(child);
+        children.sort_by(FileSystemNode::cmp_file_system_nodes);
+
+        let last = children.len() - 1;
+        for (i, c) in children.iter_mut().enumerate() {
+            match c {
+                FileSystemNode::Directory { is_last, .. }
+                | FileSystemNode::File { is_last, .. } => *is_last = i == last,
+            }
+        }
+
+        replaced
+    }
+
+    /// Inserts `child` below the chain of directories `dirs` (name and source path each),
+    /// creating the ones that do not exist yet.
+    pub fn insert_at(&mut self, dirs: &[(String, PathBuf)], child: FileSystemNode) {
+        let Some(((name, path), rest)) = dirs.split_first() else {
+            self.add_child(child);
+            return;
+        };
+        let FileSystemNode::Directory { children, .. } = self else {
+            return;
+        };
+
+        let is_dir_named = |c: &FileSystemNode| c.is_dir() && c.name().eq_ignore_ascii_case(name);
+        if !children.iter().any(is_dir_named) {
+            self.add_child(FileSystemNode::Directory {
+                name: name.clone(),
+                path: path.clone(),
+                children: Vec::new(),
+                level: 0,
+                is_last: false,
+            });
+        }
+        if let FileSystemNode::Directory { children, .. } = self {
+            if let Some(dir) = children.iter_mut().find(|c| is_dir_named(c)) {
+                dir.insert_at(rest, child);
+            }
+        }
+    }
+
+    /// Inserts `child` like `add_child`, but a directory that already exists gets the children
+    /// of `child` merged into it instead of being replaced. Returns every node that was replaced.
+    pub fn merge_child(&mut self, child: FileSystemNode) -> Vec<FileSystemNode> {
+        let existing = match self {
+            FileSystemNode::Directory { children, .. } => children
+                .iter_mut()
+                .find(|c| c.is_dir() && c.name().eq_ignore_ascii_case(child.name())),
+            FileSystemNode::File { .. } => return Vec::new(),
+        };
+
+        match (existing, child) {
+            (Some(dir), FileSystemNode::Directory { children, .. }) => children
+                .into_iter()
+                .flat_map(|c| dir.merge_child(c))
+                .collect(),
+            (_, child) => self.add_child(child).into_iter().collect(),
+        }
+    }
+}
+
+/// Extensions of archives the engine loads, left out of the base directory unless asked for
+const ARCHIVE_EXTENSIONS: &[&str] = &["VDF", "MOD"];
+
+/// Directories of version control systems, left out even when hidden entries are packed
+const VCS_DIRS: &[&str] = &[".git", ".svn", ".hg", ".bzr", "CVS"];
+
+/// Decides which directory entries are left out while building the tree.
+#[derive(Debug, Default)]
+pub struct TreeFilter {
+    respect_gitignore: bool,
+    include_archives: bool,
+    include_hidden: bool,
+    strict: bool,
+    keep_order: bool,
+    explain: bool,
+    exclude_newer_than: Option<Duration>,
+    exclude_older_than: Option<Duration>,
+    follow_symlinks: bool,
+    // The entry that could not be packed in strict mode, the walk stops there
+    failure: Option<String>,
+
+    // One matcher per `.gitignore` on the way from the base directory to the current one
+    gitignores: Vec<Gitignore>,
+    // The canonical path of every directory on the way from the base directory to the current one,
+    // a link to one of them would pack the directory into itself forever
+    ancestors: Vec<Option<PathBuf>>,
+    // How many directories deep the walk is, the base directory is 1
+    depth: usize,
+    skipped: usize,
+}
+
+impl TreeFilter {
+    pub fn respect_gitignore(mut self, respect: bool) -> Self {
+        self.respect_gitignore = respect;
+        self
+    }
+
+    /// Keeps `*.VDF` and `*.MOD` files in the base directory,
+    /// by default they are skipped since they are usually earlier builds of the archive.
+    pub fn include_archives(mut self, include: bool) -> Self {
+        self.include_archives = include;
+        self
+    }
+
+    /// Packs files and directories whose name starts with `.`, by default they are skipped.
+    /// Version control directories like `.git` are skipped either way.
+    pub fn include_hidden(mut self, include: bool) -> Self {
+        self.include_hidden = include;
+        self
+    }
+
+    /// Fails on entries that cannot be packed, like sockets or device nodes, instead of skipping them.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Why the walk stopped in strict mode, building the archive fails with it.
+    pub fn failure(&self) -> Option<&str> {
+        self.failure.as_deref()
+    }
+
+    /// Keeps entries in the order the file system lists them instead of sorting them,
+    /// the catalog is checked for siblings whose order would be ambiguous.
+    pub fn keep_order(mut self, keep: bool) -> Self {
+        self.keep_order = keep;
+        self
+    }
+
+    pub fn keeps_order(&self) -> bool {
+        self.keep_order
+    }
+
+    /// Prints which glob selected every file when the tree is built from globs.
+    pub fn explain(mut self, explain: bool) -> Self {
+        self.explain = explain;
+        self
+    }
+
+    pub fn explains(&self) -> bool {
+        self.explain
+    }
+
+    /// Leaves out files modified less than `age` ago, like ones another process is still writing.
+    pub fn exclude_newer_than(mut self, age: Option<Duration>) -> Self {
+        self.exclude_newer_than = age;
+        self
+    }
+
+    /// Leaves out files last modified more than `age` ago.
+    pub fn exclude_older_than(mut self, age: Option<Duration>) -> Self {
+        self.exclude_older_than = age;
+        self
+    }
+
+    /// Packs what symbolic links point to instead of skipping them. A link to a directory
+    /// the walk is already inside of is skipped with a warning.
+    pub fn follow_symlinks(mut self, follow: bool) -> Self {
+        self.follow_symlinks = follow;
+        self
+    }
+
+    fn filters_by_age(&self) -> bool {
+        self.exclude_newer_than.is_some() || self.exclude_older_than.is_some()
+    }
+
+    /// Picks up the `.gitignore` of `dir`, returns whether `leave` has to pop it again.
+    fn enter(&mut self, dir: &Path) -> bool {
+        self.depth += 1;
+        self.ancestors.push(dir.canonicalize().ok());
+        if !self.respect_gitignore {
+            return false;
+        }
+        let gitignore_path = dir.join(".gitignore");
+        if !gitignore_path.is_file() {
+            return false;
+        }
+
+        let (gitignore, err) = Gitignore::new(&gitignore_path);
+        if let Some(e) = err {
+            eprintln!("[WARN] {}: {}", gitignore_path.display(), e);
+        }
+        self.gitignores.push(gitignore);
+        true
+    }
+
+    fn leave(&mut self, entered: bool) {
+        self.depth -= 1;
+        self.ancestors.pop();
+        if entered {
+            self.gitignores.pop();
+        }
+    }
+
+    /// How many files and directories were left out so far.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    fn is_excluded(&mut self, path: &Path) -> bool {
+        let excluded = self.excludes(path);
+        if excluded {
+            self.skipped += 1;
+        }
+        excluded
+    }
+
+    fn excludes(&mut self, path: &Path) -> bool {
+        if self.failure.is_some() {
+            return true;
+        }
+        // Decided by the name alone, so a skipped directory is never walked
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if VCS_DIRS.contains(&name.as_ref()) && path.is_dir() {
+            eprintln!(
+                "[INFO] Skipping {}, it is version control data.",
+                path.display()
+            );
+            return true;
+        }
+        if !self.include_hidden && name.starts_with('.') {
+            eprintln!("[INFO] Skipping {}, it is hidden.", path.display());
+            return true;
+        }
+        if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink()) {
+            if !self.follow_symlinks {
+                eprintln!("[INFO] Skipping {}, it is a symbolic link.", path.display());
+                return true;
+            }
+            if path.is_dir() && self.ancestors.contains(&path.canonicalize().ok()) {
+                eprintln!(
+                    "[WARN] Skipping {}, it links to a directory it is inside of.",
+                    path.display()
+                );
+                return true;
+            }
+        }
+        // FIFOs and device nodes can block forever when read
+        let metadata = std::fs::metadata(path);
+        let problem = match &metadata {
+            Ok(m) if m.is_file() || m.is_dir() => None,
+            Ok(_) => Some("is not a regular file or directory".to_string()),
+            Err(e) => Some(e.to_string()),
+        };
+        if let Some(problem) = problem {
+            if self.strict {
+                self.failure = Some(format!("{}: {}", path.display(), problem));
+                return true;
+            }
+            eprintln!("[WARN] Skipping {}: {}", path.display(), problem);
+            return true;
+        }
+
+        if !self.include_archives && self.depth == 1 && is_archive(path) {
+            eprintln!(
+                "[INFO] Skipping {}, it looks like an archive.",
+                path.display()
+            );
+            return true;
+        }
+
+        if let Ok(m) = &metadata {
+            if m.is_file() && self.filters_by_age() && self.excludes_age(path, m) {
+                return true;
+            }
+        }
+
+        // The deepest .gitignore has the final say
+        for gitignore in self.gitignores.iter().rev() {
+            match gitignore.matched(path, path.is_dir()) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+
+    /// Whether the modification time of the file at `path` lies outside of the allowed ages.
+    fn excludes_age(&self, path: &Path, metadata: &fs::Metadata) -> bool {
+        let age = match metadata.modified() {
+            // A modification time in the future counts as just modified
+            Ok(modified) => SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default(),
+            Err(e) => {
+                eprintln!(
+                    "[WARN] Cannot read the modification time of {}, keeping it: {}",
+                    path.display(),
+                    e
+                );
+                return false;
+            }
+        };
+        if self.exclude_newer_than.is_some_and(|limit| age < limit) {
+            eprintln!(
+                "[INFO] Skipping {}, it was modified {}s ago.",
+                path.display(),
+                age.as_secs()
+            );
+            return true;
+        }
+        if self.exclude_older_than.is_some_and(|limit| age > limit) {
+            eprintln!(
+                "[INFO] Skipping {}, it was last modified {}s ago.",
+                path.display(),
+                age.as_secs()
+            );
+            return true;
+        }
+        false
+    }
 }
 
-pub fn build_file_system_tree(path: &PathBuf, lvl: i32) -> FileSystemNode {
+/// `.` and `..`, which `read_dir` leaves out but some network and FUSE file systems list,
+/// following them would pack the directory into itself forever.
+fn is_dot_entry(entry: &DirEntry) -> bool {
+    let name = entry.file_name();
+    name == "." || name == ".."
+}
+
+fn is_archive(path: &Path) -> bool {
+    path.is_file()
+        && path.extension().is_some_and(|ext| {
+            ARCHIVE_EXTENSIONS
+                .iter()
+                .any(|a| ext.eq_ignore_ascii_case(a))
+        })
+}
+
+/// The tree of `path` when it holds no more than a few regular files and no directories,
+/// read with a single `read_dir`. `None` whenever the general walk could come to a different
+/// result, like with `.gitignore` files, archives or entries that would be skipped.
+pub fn small_flat_tree(path: &Path, tree_filter: &TreeFilter) -> Option<FileSystemNode> {
+    if tree_filter.respect_gitignore || tree_filter.keep_order || tree_filter.filters_by_age() {
+        return None;
+    }
+
+    let mut children = Vec::new();
+    for entry in fs::read_dir(path).ok()? {
+        let entry = entry.ok()?;
+        if is_dot_entry(&entry) {
+            continue;
+        }
+        let entry_path = entry.path();
+        if children.len() == SMALL_DIR_ENTRIES
+            || !entry.file_type().is_ok_and(|t| t.is_file())
+            || (!tree_filter.include_hidden && entry.file_name().to_string_lossy().starts_with('.'))
+            || (!tree_filter.include_archives && is_archive(&entry_path))
+        {
+            return None;
+        }
+        children.push(FileSystemNode::File {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            path: entry_path,
+            contents: None,
+            is_last: false,
+            level: 0,
+        });
+    }
+
+    children.sort_by(FileSystemNode::cmp_file_system_nodes);
+    match children.last_mut() {
+        Some(FileSystemNode::File { is_last, .. }) => *is_last = true,
+        // Let the general walk decide what an empty directory means
+        _ => return None,
+    }
+    Some(FileSystemNode::Directory {
+        name: path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        path: path.to_path_buf(),
+        children,
+        is_last: false,
+        level: -1,
+    })
+}
+
+pub fn build_file_system_tree(
+    path: &PathBuf,
+    lvl: i32,
+    tree_filter: &mut TreeFilter,
+) -> FileSystemNode {
     if path.is_file() {
-        return FileSystemNode::File {
+        FileSystemNode::File {
             name: path.file_name().unwrap().to_string_lossy().into_owned(),
             path: path.to_path_buf(),
+            contents: None,
             is_last: false,
             level: lvl,
-        };
+        }
     } else {
-        let dir_name = path.file_name().unwrap().to_string_lossy().into_owned();
+        // The base directory may be given as `.`, its name is never written anyway
+        let dir_name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
         let mut children = Vec::new();
 
+        let entered = tree_filter.enter(path);
         if let Ok(entries) = std::fs::read_dir(path) {
-            for entry in entries {
-                if let Ok(entry) = entry {
-                    let entry_path = entry.path();
-                    children.push(build_file_system_tree(&entry_path, lvl + 1));
+            for entry in entries.flatten() {
+                let entry_path = entry.path();
+                if is_dot_entry(&entry) || tree_filter.is_excluded(&entry_path) {
+                    continue;
                 }
+                children.push(build_file_system_tree(&entry_path, lvl + 1, tree_filter));
             }
         }
+        tree_filter.leave(entered);
 
         // Sort children before creating the Directory node
-        children.sort_by(FileSystemNode::cmp_file_system_nodes);
+        if !tree_filter.keeps_order() {
+            children.sort_by(FileSystemNode::cmp_file_system_nodes);
+        }
 
         if let Some(last_node) = children.last_mut() {
             match last_node {
@@ -119,6 +921,7 @@ pub fn _build_file_system_tree_filtered(
     path: &PathBuf,
     lvl: i32,
     filter: &Vec<Vec<String>>,
+    tree_filter: &mut TreeFilter,
 ) -> Option<FileSystemNode> {
     if path.is_file() {
         let name = path.file_name().unwrap().to_string_lossy().into_owned();
@@ -126,6 +929,7 @@ pub fn _build_file_system_tree_filtered(
             Some(FileSystemNode::File {
                 name,
                 path: path.to_path_buf(),
+                contents: None,
                 is_last: false,
                 level: lvl,
             })
@@ -137,20 +941,28 @@ pub fn _build_file_system_tree_filtered(
         if is_on_level(filter, &dir_name, lvl) {
             let mut children = Vec::new();
 
+            let entered = tree_filter.enter(path);
             if let Ok(entries) = std::fs::read_dir(path) {
-                for entry in entries {
-                    if let Ok(entry) = entry {
-                        let entry_path = entry.path();
-                        let ch = _build_file_system_tree_filtered(&entry_path, lvl + 1, filter);
-                        if let Some(child) = ch {
-                            children.push(child);
-                        }
+                for entry in entries.flatten() {
+                    let entry_path = entry.path();
+                    if is_dot_entry(&entry) || tree_filter.is_excluded(&entry_path) {
+                        continue;
+                    }
+                    let ch =
+                        _build_file_system_tree_filtered(&entry_path, lvl + 1, filter, tree_filter);
+                    if let Some(child) = ch {
+                        children.push(child);
                     }
                 }
             }
+            tree_filter.leave(entered);
 
+            // A matching directory stays even when none of its files did, whether empty
+            // directories are packed is up to `BuildOptions::keep_empty_dirs` like for any other input
             // Sort children before creating the Directory node
-            children.sort_by(FileSystemNode::cmp_file_system_nodes);
+            if !tree_filter.keeps_order() {
+                children.sort_by(FileSystemNode::cmp_file_system_nodes);
+            }
 
             if let Some(last_node) = children.last_mut() {
                 match last_node {
@@ -178,28 +990,38 @@ pub fn build_file_system_tree_filtered(
     path: &PathBuf,
     lvl: i32,
     filter: &Vec<Vec<String>>,
-) -> FileSystemNode {
+    tree_filter: &mut TreeFilter,
+) -> anyhow::Result<FileSystemNode> {
     if path.is_file() {
-        println!("[ERROR] You cannot add a single dile like that!");
-        exit(1);
+        anyhow::bail!("{} is a file, not a base directory.", path.display());
     } else {
-        let dir_name = path.file_name().unwrap().to_string_lossy().into_owned();
+        // The base directory may be given as `.`, its name is never written anyway
+        let dir_name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
         let mut children = Vec::new();
 
+        let entered = tree_filter.enter(path);
         if let Ok(entries) = std::fs::read_dir(path) {
-            for entry in entries {
-                if let Ok(entry) = entry {
-                    let entry_path = entry.path();
-                    let ch = _build_file_system_tree_filtered(&entry_path, lvl + 1, filter);
-                    if let Some(child) = ch {
-                        children.push(child);
-                    }
+            for entry in entries.flatten() {
+                let entry_path = entry.path();
+                if is_dot_entry(&entry) || tree_filter.is_excluded(&entry_path) {
+                    continue;
+                }
+                let ch =
+                    _build_file_system_tree_filtered(&entry_path, lvl + 1, filter, tree_filter);
+                if let Some(child) = ch {
+                    children.push(child);
                 }
             }
         }
+        tree_filter.leave(entered);
 
         // Sort children before creating the Directory node
-        children.sort_by(FileSystemNode::cmp_file_system_nodes);
+        if !tree_filter.keeps_order() {
+            children.sort_by(FileSystemNode::cmp_file_system_nodes);
+        }
 
         if let Some(last_node) = children.last_mut() {
             match last_node {
@@ -211,13 +1033,13 @@ pub fn build_file_system_tree_filtered(
         }
 
         // this is the return
-        FileSystemNode::Directory {
+        Ok(FileSystemNode::Directory {
             name: dir_name,
             path: path.to_path_buf(),
             children,
             is_last: false,
             level: lvl,
-        }
+        })
     }
 }
 
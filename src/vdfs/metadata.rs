@@ -0,0 +1,48 @@
+use super::Vdfs;
+
+// The header has no room to spare, so metadata lives in a block between the header and the
+// catalog. `catalog_offset` points past it and all file offsets are absolute, so loaders that
+// follow the header never look at it. The block at offset 296 is laid out as
+//
+//   8 bytes  "VDFSMETA"
+//   u32 LE   length of the text that follows
+//   UTF-8    one `key=value` pair per line, each ending with `\n`
+
+const MAGIC: &[u8; 8] = b"VDFSMETA";
+
+/// The block holding `pairs`, empty when there are none.
+pub(super) fn encode(pairs: &[(String, String)]) -> Vec<u8> {
+    if pairs.is_empty() {
+        return Vec::new();
+    }
+    let text: String = pairs
+        .iter()
+        .map(|(key, value)| format!("{}={}\n", key, value))
+        .collect();
+    let mut block = MAGIC.to_vec();
+    block.extend_from_slice(&(text.len() as u32).to_le_bytes());
+    block.extend_from_slice(text.as_bytes());
+    block
+}
+
+impl Vdfs {
+    /// The key/value pairs stored with `BuildOptions::metadata`, empty for archives without them.
+    pub fn metadata(&self) -> Vec<(String, String)> {
+        let Some(rest) = self.catalog_gap.strip_prefix(MAGIC) else {
+            return Vec::new();
+        };
+        let Some(len) = rest.get(..4) else {
+            return Vec::new();
+        };
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        let Some(text) = rest.get(4..4 + len) else {
+            eprintln!("[WARN] The metadata block is cut off, ignoring it.");
+            return Vec::new();
+        };
+        String::from_utf8_lossy(text)
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+}
@@ -1,27 +1,73 @@
-use anyhow::Result;
-use chrono::{Datelike, Timelike};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Timelike};
 use core::fmt;
 use glob::{glob_with, MatchOptions};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::VecDeque,
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
     fs::{self, read_to_string, File},
-    io::{BufWriter, Write},
-    path::PathBuf,
-    process::exit,
-    time::Instant,
+    io::{self, BufWriter, Read, Write},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
+mod builder;
+mod casemap;
+mod checksums;
+mod codepage;
+mod compare;
+mod extract;
 mod filetree;
+mod metadata;
+mod reader;
+mod rewrite;
 pub mod script;
+mod split;
+mod update;
 
-use crate::vdfs::{filetree::build_file_system_tree_filtered, script::VdfsScript};
+use crate::vdfs::filetree::build_file_system_tree_filtered;
 
-use self::filetree::{build_file_system_tree, FileSystemNode};
+pub use self::builder::VdfsBuilder;
+pub use self::codepage::{truncate_encoded, DecodeFallback, Encoding};
+pub use self::compare::{ArchiveDiff, ComparisonReport};
+pub use self::extract::{ExtractOptions, FlattenCollision};
+pub use self::filetree::TreeFilter;
+pub use self::reader::ReadOptions;
+pub use self::rewrite::PathRewrite;
+pub use self::script::VdfsScript;
+pub use self::split::volume_path;
+pub use self::update::OnConflict;
+
+/// Name of the listing added by `BuildOptions::embed_manifest`
+const MANIFEST_NAME: &str = "_CONTENTS.TXT";
+pub use self::filetree::FileSystemNode;
+use self::filetree::{build_file_system_tree, small_flat_tree};
+
+/// Size of the on-disk header (comment, signature and six u32 fields)
+const HEADER_SIZE: u32 = 296;
+/// Size of the comment field at the start of the header
+pub const COMMENT_SIZE: usize = 256;
+/// Size of a single on-disk catalog entry (name and four u32 fields)
+const CATALOG_ENTRY_SIZE: u32 = 80;
+/// The only catalog layout version the engine writes and this crate understands
+const VERSION: u32 = 80;
+/// Size of the signature field that follows the comment
+pub const SIGNATURE_SIZE: usize = 16;
+/// `PSVDSC_V2.00\n\r\n\r`, the signature of every archive the original tools produce
+const SIGNATURE: [u8; SIGNATURE_SIZE] = *b"PSVDSC_V2.00\n\r\n\r";
+/// `FILE_ATTRIBUTE_READONLY` of Windows, the flag the attributes field uses for read-only files
+const ATTRIBUTE_READONLY: u32 = 0x1;
+/// Largest file FAT32 can store (4 GiB - 1 byte)
+const FAT32_MAX_FILE_SIZE: u64 = u32::MAX as u64;
 
-#[allow(dead_code)]
 #[derive(Debug)]
 pub struct VDFSHeader {
-    comment: [u8; 256],
+    comment: [u8; COMMENT_SIZE],
     signature: [u8; 16],
     num_files: u32,
     num_entries: u32,
@@ -29,18 +75,25 @@ pub struct VDFSHeader {
     size: u32,
     catalog_offset: u32,
     version: u32,
+
+    // How `comment_text` treats bytes that are not valid in `encoding`
+    comment_fallback: DecodeFallback,
+    // Codepage of the comment and all entry names
+    encoding: Encoding,
 }
 
 impl fmt::Display for VDFSHeader {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let comment = String::from_utf8_lossy(&self.comment);
         let signature = String::from_utf8_lossy(&self.signature);
 
-        writeln!(f, "Comment: {}", comment.trim_end_matches('\u{0}'))?;
+        writeln!(f, "Comment: {}", self.comment_lossy())?;
         writeln!(f, "Signature: {}", signature.trim_end_matches('\u{0}'))?;
         writeln!(f, "Number of Files: {}", self.num_files)?;
         writeln!(f, "Number of Entries: {}", self.num_entries)?;
-        writeln!(f, "Timestamp: {}", self.timestamp)?;
+        match dos_time_to_datetime(self.timestamp) {
+            Some(time) => writeln!(f, "Timestamp: {}", time)?,
+            None => writeln!(f, "Timestamp: {} (not a valid date)", self.timestamp)?,
+        }
         writeln!(f, "Size: {}", self.size)?;
         writeln!(f, "Catalog Offset: {}", self.catalog_offset)?;
         writeln!(f, "Version: {}", self.version)?;
@@ -50,8 +103,60 @@ impl fmt::Display for VDFSHeader {
 }
 
 impl VDFSHeader {
+    /// Stores `cmnt` as the comment, cut off at the last character that still fits into the field.
     fn comment(&mut self, cmnt: &str) {
-        self.comment[..cmnt.len()].copy_from_slice(cmnt.as_bytes());
+        let fitting = truncate_encoded(cmnt, self.encoding, COMMENT_SIZE);
+        if fitting.len() < cmnt.len() {
+            eprintln!(
+                "[WARN] The comment is longer than {} bytes, only its first {} characters are kept.",
+                COMMENT_SIZE,
+                fitting.chars().count()
+            );
+        }
+        let (bytes, lossy) = codepage::encode(fitting, self.encoding);
+        if lossy {
+            eprintln!(
+                "[WARN] The comment has characters that cannot be stored in {:?}, they become '?'.",
+                self.encoding
+            );
+        }
+        // A shorter comment must not leave the end of an earlier one behind
+        self.comment.fill(0x1A);
+        self.comment[..bytes.len()].copy_from_slice(&bytes);
+    }
+
+    /// The comment without the 0x1A/0x00 padding that fills the rest of the field
+    fn comment_bytes(&self) -> &[u8] {
+        let len = self
+            .comment
+            .iter()
+            .rposition(|&b| b != 0x1A && b != 0)
+            .map_or(0, |i| i + 1);
+        &self.comment[..len]
+    }
+
+    /// Decodes the comment in the archive's encoding, undecodable bytes are handled
+    /// according to the fallback the archive was opened with.
+    pub fn comment_text(&self) -> Result<String> {
+        codepage::decode(self.comment_bytes(), self.encoding, self.comment_fallback)
+    }
+
+    /// Like `comment_text`, but never fails since undecodable bytes are replaced.
+    fn comment_lossy(&self) -> String {
+        self.comment_text().unwrap_or_else(|_| {
+            codepage::decode(self.comment_bytes(), self.encoding, DecodeFallback::Replace)
+                .unwrap_or_default()
+        })
+    }
+
+    /// The version field, 80 for every archive the original tools produce.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// The signature field with the line breaks it ends with, see `Vdfs::with_signature`.
+    pub fn signature(&self) -> &[u8; SIGNATURE_SIZE] {
+        &self.signature
     }
 }
 
@@ -59,23 +164,86 @@ impl Default for VDFSHeader {
     fn default() -> Self {
         VDFSHeader {
             comment: [0x1A; 256],
-            signature: [
-                0x50, 0x53, 0x56, 0x44, 0x53, 0x43, 0x5F, 0x56, 0x32, 0x2E, 0x30, 0x30, 0x0A, 0x0D,
-                0x0A, 0x0D,
-            ], // PSVDSC_V2.00\n\r\n\r
-            timestamp: get_current_dos_time(),
+            signature: SIGNATURE,
+            timestamp: get_current_dos_time(TimeZone::default()),
             num_files: 0,
             num_entries: 0,
             size: 0,
             catalog_offset: 0,
-            version: 80,
+            version: VERSION,
+            comment_fallback: DecodeFallback::default(),
+            encoding: Encoding::default(),
+        }
+    }
+}
+
+/// The header fields decoded into plain values, for showing them in other tools.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderInfo {
+    pub comment: String,
+    /// Without the line breaks it ends with, `PSVDSC_V2.00` for valid archives
+    pub signature: String,
+    /// Number of catalog entries, directories included
+    pub num_files: u32,
+    /// Number of files
+    pub num_entries: u32,
+    /// `None` when the field is zeroed or does not hold a valid DOS date
+    pub timestamp: Option<NaiveDateTime>,
+    /// Size of the file data
+    pub size: u32,
+    pub catalog_offset: u32,
+    pub version: u32,
+}
+
+/// An entry of the archive root with everything below it added up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopLevelSummary {
+    pub name: String,
+    pub is_dir: bool,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// Which clock the DOS timestamp in the header is taken from.
+/// The original tools wrote local time, which is also what Windows tools display,
+/// but UTC gives the same timestamp regardless of where the archive is built.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TimeZone {
+    #[default]
+    Local,
+    Utc,
+}
+
+impl VDFSHeader {
+    fn with_time_zone(time_zone: TimeZone) -> Self {
+        VDFSHeader {
+            timestamp: get_current_dos_time(time_zone),
+            ..Default::default()
         }
     }
 }
 
-fn get_current_dos_time() -> u32 {
+fn get_current_dos_time(time_zone: TimeZone) -> u32 {
+    match time_zone {
+        TimeZone::Local => to_dos_time(&chrono::Local::now()),
+        TimeZone::Utc => to_dos_time(&chrono::Utc::now()),
+    }
+}
+
+/// Unpacks an MS-DOS timestamp like the one in the header, the inverse of `to_dos_time`.
+/// Seconds are stored halved and years counted from 1980. `None` when `time` is not a valid
+/// date and time, like the zeroed field of some legacy tools.
+pub fn dos_time_to_datetime(time: u32) -> Option<NaiveDateTime> {
+    let date = NaiveDate::from_ymd_opt(
+        1980 + (time >> 25) as i32,
+        (time >> 21) & 0x0F,
+        (time >> 16) & 0x1F,
+    )?;
+    date.and_hms_opt((time >> 11) & 0x1F, (time >> 5) & 0x3F, (time & 0x1F) * 2)
+}
+
+fn to_dos_time(curr: &(impl Datelike + Timelike)) -> u32 {
     let mut time: u32 = 0;
-    let curr = chrono::Utc::now();
     time |= ((curr.year() - 1980) as u32) << 25;
     time |= (curr.month0() + 1) << 21;
     time |= curr.day() << 16;
@@ -86,43 +254,125 @@ fn get_current_dos_time() -> u32 {
     time
 }
 
+/// The DOS time of `secs` seconds since the Unix epoch, read as UTC like `SOURCE_DATE_EPOCH`.
+/// `None` outside of the years 1980 to 2107 a DOS time can hold.
+pub fn unix_to_dos_time(secs: i64) -> Option<u32> {
+    let time = chrono::DateTime::from_timestamp(secs, 0)?;
+    (1980..=2107)
+        .contains(&time.year())
+        .then(|| to_dos_time(&time))
+}
+
+#[repr(u32)]
 enum EntryType {
     Dir = 0x80000000,
     LastFile = 0x40000000,
 }
 
-#[allow(dead_code)]
 #[derive(Debug)]
 pub struct VDFSCatalogEntry {
     name_utf8: String,
     name: [u8; 64],
     next_index: u32,
     size: u32,
+    // Both are kept verbatim when an archive is read, only the Dir and LastFile bits
+    // of `typ` are interpreted, everything else is passed through to the engine untouched
     typ: u32,
     attributes: u32,
+    /// Codepage `name` is stored in
+    encoding: Encoding,
 
     parent_id: i32,
     is_dir: bool,
 }
 impl VDFSCatalogEntry {
-    fn new(file_name: &str) -> VDFSCatalogEntry {
+    fn new(file_name: &str, encoding: Encoding) -> Result<VDFSCatalogEntry> {
         let mut vdfs = VDFSCatalogEntry::default();
-        vdfs.name[..file_name.len()].copy_from_slice(file_name.to_ascii_uppercase().as_bytes());
-        vdfs.name_utf8 = file_name.to_string();
-        vdfs
+        vdfs.set_name(file_name, encoding)?;
+        Ok(vdfs)
     }
-    fn new_sized(file_name: &str, size: u64) -> VDFSCatalogEntry {
+    fn new_sized(file_name: &str, size: u64, encoding: Encoding) -> Result<VDFSCatalogEntry> {
         let mut vdfs = VDFSCatalogEntry::default();
-        vdfs.name[..file_name.len()].copy_from_slice(file_name.to_ascii_uppercase().as_bytes());
-        vdfs.name_utf8 = file_name.to_string();
+        vdfs.set_name(file_name, encoding)?;
         vdfs.size = size as u32;
-        vdfs
+        Ok(vdfs)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    /// Fails for names with characters `encoding` cannot represent, the engine would look them
+    /// up under another name, and for names taking more than the 64 bytes of the field once encoded.
+    fn set_name(&mut self, file_name: &str, encoding: Encoding) -> Result<()> {
+        let (bytes, lossy) = codepage::encode(file_name, encoding);
+        if lossy {
+            let unencodable: String = file_name
+                .chars()
+                .filter(|c| codepage::encode(c.encode_utf8(&mut [0; 4]), encoding).1)
+                .collect();
+            bail!(
+                "{} has characters that cannot be stored in {:?}: {}",
+                file_name,
+                encoding,
+                unencodable
+            );
+        }
+        if bytes.len() > self.name.len() {
+            bail!(
+                "{} takes {} bytes in {:?}, names in an archive are limited to {}.",
+                file_name,
+                bytes.len(),
+                encoding,
+                self.name.len()
+            );
+        }
+        self.name = encode_entry_name(file_name, encoding);
+        self.name_utf8 = file_name.to_string();
+        self.encoding = encoding;
+        Ok(())
     }
+
+    /// Stores the name in its original case instead of uppercase, see `BuildOptions::preserve_case`.
+    fn keep_case(&mut self, encoding: Encoding) {
+        self.name = encode_name(&self.name_utf8, encoding);
+    }
+}
+
+/// The name as it is written to the catalog, uppercase in `encoding` and padded with spaces (0x20).
+/// Only ASCII letters are uppercased, before encoding so multi-byte characters stay intact.
+/// Gives the exact bytes to compare catalog names against. Names over 64 bytes are cut off
+/// after the last whole character that fits, so a double-byte character is never split.
+pub fn encode_entry_name(file_name: &str, encoding: Encoding) -> [u8; 64] {
+    encode_name(&file_name.to_ascii_uppercase(), encoding)
+}
+
+/// Like `encode_entry_name`, but keeps the case of `file_name`.
+fn encode_name(file_name: &str, encoding: Encoding) -> [u8; 64] {
+    let mut name = [0x20; 64];
+    let (bytes, _) = codepage::encode(truncate_encoded(file_name, encoding, name.len()), encoding);
+    name[..bytes.len()].copy_from_slice(&bytes);
+    name
+}
+
+/// The name stored in a catalog entry without its padding, bytes that are not valid
+/// in `encoding` become U+FFFD.
+pub fn decode_entry_name(name: &[u8; 64], encoding: Encoding) -> String {
+    let len = name
+        .iter()
+        .rposition(|&b| b != b' ' && b != 0)
+        .map_or(0, |i| i + 1);
+    codepage::decode(&name[..len], encoding, DecodeFallback::Replace).unwrap_or_default()
 }
 
 impl fmt::Display for VDFSCatalogEntry {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let name = String::from_utf8_lossy(&self.name);
+        // The name decoded with the archive's codepage, without the space padding
+        let name = if self.name_utf8.is_empty() {
+            decode_entry_name(&self.name, self.encoding)
+        } else {
+            self.name_utf8.trim_end_matches([' ', '\0']).to_string()
+        };
 
         writeln!(f, "Name: {}", name)?;
         writeln!(f, "Offset: {}", self.next_index)?;
@@ -145,6 +395,7 @@ impl Default for VDFSCatalogEntry {
             size: 0,
             typ: 0,
             attributes: 0,
+            encoding: Encoding::default(),
 
             parent_id: 0,
             is_dir: false,
@@ -152,21 +403,282 @@ impl Default for VDFSCatalogEntry {
     }
 }
 
+/// Settings that shape the archive while it is being built.
+#[derive(Debug, Default)]
+pub struct BuildOptions {
+    pub tree_filter: TreeFilter,
+    /// Adds a listing of all entries as `_CONTENTS.TXT` to the archive root
+    pub embed_manifest: bool,
+    pub time_zone: TimeZone,
+    /// Writes 0 as the header timestamp like some legacy tools, for reproducing their archives
+    pub no_timestamp: bool,
+    /// DOS time written into the header instead of the current time, for reproducible builds
+    pub timestamp: Option<u32>,
+    /// Version written into the header instead of 80, for engine forks that expect another one
+    pub version: Option<u32>,
+    /// Signature written into the header instead of `PSVDSC_V2.00`, for engine forks that expect another one
+    pub signature: Option<[u8; SIGNATURE_SIZE]>,
+    /// Applied in order to the logical path of every file before anything else
+    pub path_rewrites: Vec<PathRewrite>,
+    /// Keeps directories without any files as entries of their own instead of leaving them out
+    pub keep_empty_dirs: bool,
+    /// Puts everything into a single top-level directory of this name
+    pub root_name: Option<String>,
+    /// Puts every file directly into the archive root without any directories
+    pub flatten: bool,
+    /// Orders each directory strictly by stored name, directories are not put first
+    pub sorted_catalog: bool,
+    pub catalog_layout: CatalogLayout,
+    /// Codepage the names and the comment are stored in
+    pub encoding: Encoding,
+    /// Stores files with identical contents only once, their entries share the offset.
+    /// Not every loader expects shared offsets.
+    pub dedup: bool,
+    /// Stores the CRC32 of every file in the otherwise unused attributes field of its entry
+    pub crc_in_attributes: bool,
+    /// Reads the files on all cores when `dedup` or `crc_in_attributes` need their contents
+    /// before the catalog is built, the archive comes out the same
+    pub parallel_read: bool,
+    /// Marks read-only source files with `ATTRIBUTE_READONLY` in the attributes of their entry
+    pub preserve_attributes: bool,
+    /// Writes names in their original case instead of uppercase, for tools that read the catalog
+    /// case-sensitively. The engine expects uppercase names and the catalog is still sorted by them.
+    pub preserve_case: bool,
+    /// Reads the archive back after writing it and compares it to what was packed
+    pub verify_after_pack: bool,
+    /// Fails when two files anywhere in the archive share a name, for loaders that
+    /// look files up by name alone
+    pub unique_basenames: bool,
+    /// Pads the file data with zeros to this many bytes, so files can later be replaced in place.
+    /// The header size keeps counting only the bytes in use.
+    pub reserve: Option<u64>,
+    /// Key/value pairs stored in a block between the header and the catalog, read back with `Vdfs::metadata`
+    pub metadata: Vec<(String, String)>,
+    /// Fails the build unless the archive ends up with exactly this many files
+    pub expect_files: Option<u32>,
+    /// Logical paths in the order their entries should have in the catalog, as far as
+    /// directories allow. Entries not listed follow the listed ones of their directory.
+    pub order: Vec<String>,
+    /// Logical paths of entries that are moved to the start or end of their directory,
+    /// for controlling which of them the engine lets win
+    pub priority: Vec<String>,
+    pub priority_placement: PriorityPlacement,
+    /// Where to write the original spelling of every path, the archive only stores uppercase names
+    pub case_map: Option<PathBuf>,
+    /// Where to write the CRC32 of every file, see `Vdfs::write_checksums`
+    pub checksums: Option<PathBuf>,
+    /// Checked before every file is read, setting it stops the build with `Cancelled`
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Told about every file as the catalog is built and every piece of data as it is written
+    pub progress: Option<Arc<dyn ProgressSink>>,
+    /// Byte order of the header and catalog fields, anything but little-endian
+    /// gives an archive no loader can read
+    pub debug_endianness: Endianness,
+}
+
+/// The logical paths listed in the order file `path`, for `BuildOptions::order`.
+/// Empty lines and ones starting with `#` are skipped.
+pub fn read_order_file(path: &Path) -> Result<Vec<String>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Could not read the order file {}", path.display()))?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// The error a build stopped through `BuildOptions::cancel` fails with.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The build was cancelled.")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Receives the progress of a build, set through `BuildOptions::progress`. Calls come from
+/// the thread that builds or writes the archive, one after the other.
+pub trait ProgressSink: Send + Sync {
+    /// Called once the catalog entry of a file is built, with its source path and size.
+    fn file_done(&self, name: &str, bytes: u64);
+
+    /// Called by `write_to` after every file or padding is written, with the bytes of
+    /// file data written so far and in total.
+    fn bytes_written(&self, _written: u64, _total: u64) {}
+}
+
+impl fmt::Debug for dyn ProgressSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ProgressSink")
+    }
+}
+
+/// Where the entries of a priority list go within their directory.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PriorityPlacement {
+    #[default]
+    First,
+    Last,
+}
+
+/// Which globs of a script decide about a file both an include and an exclude glob match.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precedence {
+    #[default]
+    Exclude,
+    Include,
+}
+
+/// Byte order `Vdfs::save_to_file` writes the numbers of the header and the catalog in.
+/// The format is always little-endian, big-endian only exists to test other parsers.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Endianness {
+    #[default]
+    Little,
+    /// Not a valid archive, only for debugging parsers
+    Big,
+}
+
+/// How the entries of each directory are ordered in the catalog.
+/// The children of a directory always form one run, this only decides the order within it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CatalogLayout {
+    /// Subdirectories first, then files, each sorted by name, like the original tools
+    #[default]
+    Grouped,
+    /// Subdirectories and files mixed, sorted by name only
+    Interleaved,
+}
+
+/// What happened while an archive was built, see `Vdfs::stats`.
+#[derive(Debug, Default, Clone)]
+pub struct BuildStats {
+    pub files: usize,
+    pub dirs: usize,
+    /// Sum of all file sizes, shared contents are counted for every entry
+    pub total_bytes: u64,
+    /// Time spent building the catalog and reading the files
+    pub duration: Duration,
+    /// Files and directories the tree filter left out
+    pub skipped: usize,
+}
+
+/// Header values for `Vdfs::with_raw_header_overrides`, `None` keeps the computed value.
+#[doc(hidden)]
+#[derive(Debug, Default, Clone)]
+pub struct RawHeaderOverrides {
+    pub num_files: Option<u32>,
+    pub num_entries: Option<u32>,
+    pub size: Option<u32>,
+    pub catalog_offset: Option<u32>,
+    pub version: Option<u32>,
+}
+
+/// An archive, either built from files or read with `Vdfs::from_file`.
+///
+/// A read archive keeps the whole file in memory and never touches it again, so every
+/// `&self` method, like `open_entry`, `extract_file` or `offset_of`, can be called from
+/// any number of threads at once, for example through an `Arc<Vdfs>` shared by the
+/// workers of an asset server. Methods taking `&mut self` need exclusive access as usual.
 #[derive(Debug)]
 pub struct Vdfs {
     pub header: VDFSHeader,
     pub fs: FileSystemNode,
 
     pub catalog_dirs: Vec<VDFSCatalogEntry>,
+    /// The file data of a read archive, built archives keep theirs in chunks that are only read while writing
     pub data: Vec<u8>,
     pub curr_pos: u32,
+
+    // Logical path of every catalog entry and the uppercased paths pointing back at them
+    entry_paths: Vec<String>,
+    path_index: HashMap<String, usize>,
+    // Whether files with identical contents share one copy of the data
+    dedup: bool,
+    // Whether the attributes of file entries hold the CRC32 of their contents
+    crc_in_attributes: bool,
+    // Whether the files are read on all cores, see `BuildOptions::parallel_read`
+    parallel_read: bool,
+    // Whether read-only source files get `ATTRIBUTE_READONLY`
+    preserve_attributes: bool,
+    // Whether names keep their original case, see `BuildOptions::preserve_case`
+    preserve_case: bool,
+    // Byte order of the numbers in the header and catalog, see `BuildOptions::debug_endianness`
+    endianness: Endianness,
+    // Whether the archive was read from a file, its tree then only mirrors the catalog
+    // and the files have no source on disk
+    opened: bool,
+    stats: BuildStats,
+    // Whatever lies between the header and the catalog, the metadata block or unknown bytes
+    // of a read archive, written back as is so the catalog stays where the header says it is
+    catalog_gap: Vec<u8>,
+    // The file data of a built archive by start offset, written one after the other behind the catalog
+    chunks: Vec<(u64, DataChunk)>,
+    // Where the build and `save_to_file` report their progress, see `BuildOptions::progress`
+    progress: Option<Arc<dyn ProgressSink>>,
+    // How the build ordered, checked and padded the archive, `add_raw_entry` does it again
+    rebuild: RebuildLayout,
+}
+
+/// The parts of `BuildOptions` that shape the catalog and data after the tree is prepared.
+#[derive(Debug, Default, Clone, Copy)]
+struct RebuildLayout {
+    /// Whether subdirectories come before files when sorting by stored name,
+    /// `None` when the input order is kept
+    dirs_first: Option<bool>,
+    sorted_catalog: bool,
+    unique_basenames: bool,
+    reserve: Option<u64>,
+}
+
+/// A piece of the file data of a built archive.
+#[derive(Debug, Clone)]
+enum DataChunk {
+    /// Contents that never existed on disk
+    Bytes(Vec<u8>),
+    /// A source file, copied into the archive while writing so the data never has to fit into memory
+    File { path: PathBuf, size: u32 },
+    /// Padding up to the reserved size
+    Zeros(u64),
+}
+
+impl DataChunk {
+    fn len(&self) -> u64 {
+        match self {
+            DataChunk::Bytes(bytes) => bytes.len() as u64,
+            DataChunk::File { size, .. } => *size as u64,
+            DataChunk::Zeros(size) => *size,
+        }
+    }
 }
 
+// Sharing a read archive between threads relies on this, see the docs of `Vdfs`
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Vdfs>();
+};
+
 impl fmt::Display for Vdfs {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "VDFS Header:")?;
         writeln!(f, "{}", self.header)?;
 
+        let metadata = self.metadata();
+        if !metadata.is_empty() {
+            writeln!(f, "VDFS Metadata:")?;
+            for (key, value) in metadata {
+                writeln!(f, "{}: {}", key, value)?;
+            }
+            writeln!(f)?;
+        }
+
         writeln!(f, "VDFS Catalog:")?;
         for (i, entry) in self.catalog_dirs.iter().enumerate() {
             writeln!(f, "{i}\n{}\n", entry)?;
@@ -177,137 +689,482 @@ impl fmt::Display for Vdfs {
 }
 
 impl Vdfs {
-    pub fn from_dir(path: &mut PathBuf) -> Self {
+    pub fn from_dir(path: &Path, options: BuildOptions) -> Result<Self> {
+        VdfsBuilder::new(options).add_dir(path).build()
+    }
+
+    /// Packs the contents of all `dirs` into the archive root, directories they share are merged.
+    /// A file of a later directory replaces the one at the same path of an earlier directory.
+    pub fn from_dirs(dirs: &[PathBuf], options: BuildOptions) -> Result<Self> {
+        dirs.iter()
+            .fold(VdfsBuilder::new(options), |builder, dir| {
+                builder.add_dir(dir)
+            })
+            .build()
+    }
+
+    /// Builds the catalog and data of an archive holding everything in `fs`.
+    /// Fails when the build is cancelled or the archive outgrows the 32 bit sizes and offsets.
+    fn from_tree(fs: FileSystemNode, options: &BuildOptions) -> Result<Self> {
+        let catalog_gap = metadata::encode(&options.metadata);
+        Self::from_tree_with_gap(fs, options, catalog_gap, !options.keep_empty_dirs)
+    }
+
+    /// Like `from_tree` but with `catalog_gap` between the header and the catalog instead of the
+    /// metadata of `options`, empty directories are only left out with `drop_empty_dirs`.
+    fn from_tree_with_gap(
+        fs: FileSystemNode,
+        options: &BuildOptions,
+        catalog_gap: Vec<u8>,
+        drop_empty_dirs: bool,
+    ) -> Result<Self> {
+        let time = Instant::now();
+        if let Some(failure) = options.tree_filter.failure() {
+            bail!("{}", failure);
+        }
+        // Almost always a wrong path or a filter that is too eager
+        if fs.flatten().iter().all(|(_, is_dir)| *is_dir) {
+            if options.tree_filter.is_strict() {
+                bail!("There are no files to pack.");
+            }
+            eprintln!("[WARN] There are no files to pack, the archive will be empty.");
+        }
         let mut vdfs = Vdfs {
-            header: VDFSHeader::default(),
-            fs: build_file_system_tree(path, -1),
+            header: VDFSHeader {
+                encoding: options.encoding,
+                ..VDFSHeader::with_time_zone(options.time_zone)
+            },
+            fs,
             catalog_dirs: Vec::new(),
             data: Vec::new(),
             curr_pos: 0,
+            entry_paths: Vec::new(),
+            path_index: HashMap::new(),
+            dedup: options.dedup,
+            crc_in_attributes: options.crc_in_attributes,
+            parallel_read: options.parallel_read,
+            preserve_attributes: options.preserve_attributes,
+            preserve_case: options.preserve_case,
+            endianness: options.debug_endianness,
+            opened: false,
+            stats: BuildStats::default(),
+            catalog_gap,
+            chunks: Vec::new(),
+            progress: options.progress.clone(),
+            rebuild: RebuildLayout {
+                dirs_first: (!options.tree_filter.keeps_order()).then_some(
+                    !options.sorted_catalog && options.catalog_layout == CatalogLayout::Grouped,
+                ),
+                sorted_catalog: options.sorted_catalog,
+                unique_basenames: options.unique_basenames,
+                reserve: options.reserve,
+            },
+        };
+
+        if options.no_timestamp {
+            vdfs.header.timestamp = 0;
+        } else if let Some(timestamp) = options.timestamp {
+            vdfs.header.timestamp = timestamp;
+        }
+        if let Some(version) = options.version {
+            vdfs.header.version = version;
+        }
+        if let Some(signature) = options.signature {
+            vdfs.header.signature = signature;
+        }
+
+        vdfs.prepare_tree(options, drop_empty_dirs)?;
+        vdfs.build_catalog(options.cancel.as_deref())?;
+        vdfs.check_catalog()?;
+        if let Some(expected) = options.expect_files {
+            if vdfs.header.num_entries != expected {
+                bail!(
+                    "Expected {} files but the archive has {}.",
+                    expected,
+                    vdfs.header.num_entries
+                );
+            }
+        }
+        vdfs.lay_out_data()?;
+        vdfs.count_stats();
+        vdfs.stats.duration = time.elapsed();
+        vdfs.stats.skipped = options.tree_filter.skipped();
+        eprintln!(
+            "[INFO] Packed {} files in {} directories ({} bytes), skipped {}.",
+            vdfs.stats.files, vdfs.stats.dirs, vdfs.stats.total_bytes, vdfs.stats.skipped
+        );
+        Ok(vdfs)
+    }
+
+    /// Statistics of the build that produced this archive, all zero for opened archives.
+    pub fn stats(&self) -> &BuildStats {
+        &self.stats
+    }
+
+    fn count_stats(&mut self) {
+        self.stats.dirs = self.catalog_dirs.iter().filter(|e| e.is_dir).count();
+        self.stats.files = self.catalog_dirs.len() - self.stats.dirs;
+        self.stats.total_bytes = self
+            .catalog_dirs
+            .iter()
+            .filter(|e| !e.is_dir)
+            .map(|e| e.size as u64)
+            .sum();
+    }
+
+    /// Builds the file tree of a directory without reading any file contents.
+    pub fn tree_from_dir(path: &PathBuf, tree_filter: &mut TreeFilter) -> FileSystemNode {
+        // Packing many tiny directories in a row is common in test suites,
+        // a few files without subdirectories do not need the recursive walk
+        if let Some(tree) = small_flat_tree(path, tree_filter) {
+            return tree;
+        }
+        build_file_system_tree(path, -1, tree_filter)
+    }
+
+    /// Builds an archive that has every source file of `pairs` at the archive path next to it,
+    /// directories on the way are created. A later pair for the same path replaces an earlier one.
+    pub fn from_mapping(pairs: Vec<(PathBuf, String)>, options: BuildOptions) -> Result<Vdfs> {
+        pairs
+            .into_iter()
+            .fold(VdfsBuilder::new(options), |builder, (source, path)| {
+                builder.add_file(&path, &source)
+            })
+            .build()
+    }
+
+    /// Builds the file tree of the files below `base_dir` matching any of `globs`,
+    /// the same way a script selects them.
+    pub fn tree_from_globs(
+        base_dir: &Path,
+        globs: &[&str],
+        tree_filter: &mut TreeFilter,
+    ) -> Result<FileSystemNode> {
+        let script = VdfsScript {
+            comment: "".into(),
+            base_dir: base_dir.to_path_buf(),
+            file_path: PathBuf::new(),
+            file_include_globs: globs.iter().map(|g| (*g).into()).collect(),
+            priority: Vec::new(),
+            priority_placement: PriorityPlacement::default(),
+            path_rewrites: Vec::new(),
+            file_mapping: Vec::new(),
+            file_exclude_globs: Vec::new(),
+            precedence: Precedence::default(),
         };
+        script_tree(&script, &None, tree_filter)
+    }
 
-        vdfs.build_catalog();
-        // bfs(&vdfs.fs);
-        vdfs.calculate_data_size();
-        vdfs
+    /// Builds the file tree a script selects without reading any file contents.
+    pub fn tree_from_script(
+        path: &PathBuf,
+        base_dir_override: &Option<PathBuf>,
+        tree_filter: &mut TreeFilter,
+    ) -> Result<FileSystemNode> {
+        let source = read_to_string(path)
+            .with_context(|| format!("Could not read the script {}", path.display()))?;
+        let script = VdfsScript::parse(path, &source)
+            .with_context(|| format!("Could not parse {}", path.display()))?;
+        script
+            .validate()
+            .with_context(|| format!("{} is not a valid script", path.display()))?;
+        script_tree(&script, base_dir_override, tree_filter)
     }
 
+    /// Builds the archive the script at `path` describes without writing it, so it can be
+    /// inspected or changed first. Where it goes is up to the caller, usually the `file_path`
+    /// of the script. `comment_override` replaces the comment of the script.
     pub fn from_script(
         path: &PathBuf,
         base_dir_override: &Option<PathBuf>,
-        output_file_override: &Option<PathBuf>,
         comment_override: &Option<String>,
-    ) -> Result<()> {
+        mut options: BuildOptions,
+    ) -> Result<Self> {
         let time = Instant::now();
-        println!("[INFO] Generating archive: {}", path.display());
-        let yml_file = read_to_string(path).unwrap();
-        let script = VdfsScript::from_yaml(&yml_file).unwrap();
+        eprintln!("[INFO] Generating archive: {}", path.display());
+        let source = read_to_string(path)
+            .with_context(|| format!("Could not read the script {}", path.display()))?;
+        let script = VdfsScript::parse(path, &source)
+            .with_context(|| format!("Could not parse {}", path.display()))?;
+        script
+            .validate()
+            .with_context(|| format!("{} is not a valid script", path.display()))?;
+
+        let fs = script_tree(&script, base_dir_override, &mut options.tree_filter)?;
+        options.priority = script.priority.iter().map(|p| p.to_string()).collect();
+        options.priority_placement = script.priority_placement;
+        options.path_rewrites.splice(0..0, script.path_rewrites()?);
+
+        let vdfs = Vdfs::from_tree(fs, &options)?;
+        eprintln!("[INFO] Done: {:.2?}", time.elapsed());
+        Ok(vdfs.add_comment(match comment_override {
+            Some(s) => Some(s),
+            None => Some(&script.comment),
+        }))
+    }
+
+    /// Applies the tree transformations requested in `options` before the catalog is built.
+    fn prepare_tree(&mut self, options: &BuildOptions, drop_empty_dirs: bool) -> Result<()> {
+        if drop_empty_dirs {
+            let dropped = self.fs.drop_empty_dirs();
+            if dropped > 0 {
+                eprintln!("[INFO] Left out {} empty directories.", dropped);
+            }
+        }
+        if !options.path_rewrites.is_empty() {
+            let rewrite = |path: &str| {
+                options
+                    .path_rewrites
+                    .iter()
+                    .fold(path.to_string(), |path, r| r.apply(&path))
+            };
+            self.fs.rewrite_paths(rewrite)?;
+        }
+        if options.flatten {
+            self.fs.flatten_into_root()?;
+        }
+        if let Some(root_name) = &options.root_name {
+            if root_name.is_empty() || root_name.contains(['/', '\\']) {
+                bail!("{} is not a valid name for the root directory.", root_name);
+            }
+            self.fs.wrap_children(root_name);
+        }
+        if options.embed_manifest {
+            self.embed_manifest();
+        }
+        // The tree is sorted by UTF-8 name while it is collected, the catalog compares the encoded
+        // names, which only differ for characters outside of ASCII
+        if let Some(dirs_first) = self.rebuild.dirs_first {
+            self.fs
+                .sort_by_stored_name(self.header.encoding, dirs_first);
+        }
+        if !options.order.is_empty() {
+            self.apply_order(&options.order);
+        }
+        self.apply_priority(options);
+        Ok(())
+    }
 
-        // println!("{:#?}", script);
+    /// Sorts the entries of every directory by where their path appears in `order`.
+    fn apply_order(&mut self, order: &[String]) {
+        let mut ranks = HashMap::new();
+        for (rank, path) in order.iter().enumerate() {
+            let key = path
+                .split(['/', '\\'])
+                .filter(|c| !c.is_empty())
+                .collect::<Vec<_>>()
+                .join("/")
+                .to_ascii_uppercase();
+            ranks.entry(key).or_insert(rank);
+        }
 
-        if script.base_dir.as_os_str().is_empty() && base_dir_override.is_none() {
-            println!(
-                "[ERROR] Empty base directory path in script file and no override was provided."
+        let known: HashSet<String> = self
+            .fs
+            .flatten()
+            .into_iter()
+            .map(|(path, _)| path.to_ascii_uppercase())
+            .collect();
+        let unknown = ranks.keys().filter(|p| !known.contains(*p)).count();
+        if unknown > 0 {
+            eprintln!(
+                "[WARN] {} paths of the order list are not in the archive.",
+                unknown
             );
-            exit(1)
-        } else if script.file_path.as_os_str().is_empty() && output_file_override.is_none() {
-            println!("[ERROR] Empty output path in script file and no override was provided.");
-            exit(1)
         }
+        self.fs.sort_by_rank("", &ranks);
+    }
 
-        let path_filter_globs: Vec<_> = script
-            .file_include_globs
-            .iter()
-            .flat_map(|g| {
-                let glb = format!(
-                    "{}/{}",
-                    case_insensitive_globify(&match base_dir_override {
-                        Some(pb) => pb.to_string_lossy(),
-                        None => script.base_dir.to_string_lossy(),
-                    }),
-                    case_insensitive_globify(g)
+    /// Moves the entries of the priority list to the start or end of their directory,
+    /// keeping them in the order they are listed.
+    fn apply_priority(&mut self, options: &BuildOptions) {
+        let to_front = options.priority_placement == PriorityPlacement::First;
+        let mut paths: Vec<_> = options.priority.iter().collect();
+        if to_front {
+            // Moving them to the front one by one reverses them
+            paths.reverse();
+        }
+        for path in paths {
+            let components: Vec<_> = path.split(['/', '\\']).filter(|c| !c.is_empty()).collect();
+            if !self.fs.move_child(&components, to_front) {
+                eprintln!("[WARN] The priority entry {} is not in the archive.", path);
+            }
+        }
+    }
+
+    /// Runs the checks the build asked for on the built catalog.
+    fn check_catalog(&self) -> Result<()> {
+        self.check_duplicate_names()?;
+        if self.rebuild.sorted_catalog {
+            self.check_sorted_catalog()?;
+        }
+        if self.rebuild.dirs_first.is_none() {
+            self.check_sibling_order()?;
+        }
+        if self.rebuild.unique_basenames {
+            self.check_unique_basenames()?;
+        }
+        Ok(())
+    }
+
+    /// Makes sure the uppercase names in every directory of the built catalog are strictly increasing,
+    /// which is what loaders that binary-search the catalog rely on.
+    fn check_sorted_catalog(&self) -> Result<()> {
+        let encoding = self.header.encoding;
+        for (i, pair) in self.catalog_dirs.windows(2).enumerate() {
+            let (a, b) = (&pair[0], &pair[1]);
+            if a.parent_id == b.parent_id
+                && encode_entry_name(&a.name_utf8, encoding)
+                    >= encode_entry_name(&b.name_utf8, encoding)
+            {
+                bail!(
+                    "{} and {} are not in strictly increasing order in the catalog.",
+                    self.entry_paths[i],
+                    self.entry_paths[i + 1]
                 );
-                // println!("glob: {}", glb);
-                glob_with(
-                    &glb,
-                    MatchOptions {
-                        case_sensitive: false,
-                        require_literal_separator: false,
-                        require_literal_leading_dot: false,
-                    },
-                )
-            })
-            .collect();
+            }
+        }
+        Ok(())
+    }
 
-        let mut path_filter: Vec<Vec<String>> = Vec::new();
-        // println!("{:#?}", path_filter);
-
-        for paths in path_filter_globs {
-            for p in paths {
-                if let Ok(path) = p {
-                    path_filter.push({
-                        let pth = path
-                            .strip_prefix(match base_dir_override {
-                                Some(pb) => {
-                                    // let mut pb = pb.clone();
-                                    // pb.pop();
-                                    pb
-                                }
-                                None => {
-                                    // let mut bd = script.base_dir.clone();
-                                    // bd.pop();
-                                    // bd
-                                    &script.base_dir
-                                }
-                            })
-                            .unwrap();
-                        pth.iter()
-                            .map(|component| component.to_string_lossy().to_string())
-                            .collect()
-                    });
+    /// Makes sure a catalog built from unsorted input is still valid: every directory's entries
+    /// are one run that ends with the only `LastFile` of the run.
+    fn check_sibling_order(&self) -> Result<()> {
+        for (i, e) in self.catalog_dirs.iter().enumerate() {
+            let is_last = e.typ & EntryType::LastFile as u32 != 0;
+            let ends_run = self
+                .catalog_dirs
+                .get(i + 1)
+                .is_none_or(|next| next.parent_id != e.parent_id);
+            if is_last != ends_run {
+                bail!(
+                    "{} is not where its directory's entries end, the catalog would be invalid.",
+                    self.entry_paths[i]
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes sure no two entries of a directory end up with the same uppercase name, like
+    /// `Readme.txt` and `README.TXT` from a case-sensitive file system. The engine would only see one.
+    fn check_duplicate_names(&self) -> Result<()> {
+        let mut seen: HashMap<(i32, [u8; 64]), usize> = HashMap::new();
+        let mut clashes = Vec::new();
+        for (i, e) in self.catalog_dirs.iter().enumerate() {
+            let name = encode_entry_name(&e.name_utf8, self.header.encoding);
+            if let Some(&first) = seen.get(&(e.parent_id, name)) {
+                clashes.push(format!(
+                    "{} and {}",
+                    self.entry_paths[first], self.entry_paths[i]
+                ));
+            } else {
+                seen.insert((e.parent_id, name), i);
+            }
+        }
+        if !clashes.is_empty() {
+            bail!(
+                "{} entries share their name with another one in the same directory, the engine would only see one of them: {}",
+                clashes.len(),
+                clashes.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Makes sure no two files share an uppercase name, no matter which directories they are in.
+    fn check_unique_basenames(&self) -> Result<()> {
+        let mut seen: HashMap<[u8; 64], usize> = HashMap::new();
+        let mut clashes = Vec::new();
+        for (i, e) in self.catalog_dirs.iter().enumerate() {
+            if e.is_dir {
+                continue;
+            }
+            let name = encode_entry_name(&e.name_utf8, self.header.encoding);
+            if let Some(&first) = seen.get(&name) {
+                clashes.push(format!(
+                    "{} and {}",
+                    self.entry_paths[first], self.entry_paths[i]
+                ));
+            } else {
+                seen.insert(name, i);
+            }
+        }
+        if !clashes.is_empty() {
+            bail!(
+                "{} files share their name with another one, loaders that ignore directories would only see one of them: {}",
+                clashes.len(),
+                clashes.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Adds a text listing of everything in the tree as a file in the archive root.
+    fn embed_manifest(&mut self) {
+        let mut listing = String::new();
+        let mut queue = VecDeque::new();
+        queue.push_back((String::new(), &self.fs));
+
+        while let Some((prefix, node)) = queue.pop_front() {
+            match node {
+                FileSystemNode::Directory { name, children, .. } => {
+                    let path = if node == &self.fs {
+                        String::new()
+                    } else {
+                        let path = format!("{}{}\\", prefix, name);
+                        listing.push_str(&format!("{}\r\n", path));
+                        path
+                    };
+                    for child in children {
+                        queue.push_back((path.clone(), child));
+                    }
+                }
+                FileSystemNode::File {
+                    name,
+                    path,
+                    contents,
+                    ..
+                } => {
+                    let size = match contents {
+                        Some(c) => c.len() as u64,
+                        None => fs::metadata(path).map(|m| m.len()).unwrap_or_default(),
+                    };
+                    listing.push_str(&format!("{}{}\t{}\r\n", prefix, name, size));
                 }
             }
         }
 
-        let mut vdfs = Vdfs {
-            header: VDFSHeader::default(),
-            fs: build_file_system_tree_filtered(
-                match base_dir_override {
-                    Some(pb) => pb,
-                    None => &script.base_dir,
-                },
-                -1,
-                &path_filter,
-            ),
-            catalog_dirs: Vec::new(),
-            data: Vec::new(),
-            curr_pos: 0,
+        let manifest = FileSystemNode::File {
+            name: MANIFEST_NAME.to_string(),
+            path: PathBuf::from(MANIFEST_NAME),
+            contents: Some(listing.into_bytes()),
+            is_last: false,
+            level: 0,
         };
-        // println!("-------");
-        // bfs(&vdfs.fs);
-        // println!("-------");
-        // println!("{:#?}", path_filter);
-
-        vdfs.build_catalog();
-
-        // bfs(&vdfs.fs);
-        // println!("{}", vdfs);
-        vdfs.calculate_data_size();
-        println!("[INFO] Done: {:.2?}", time.elapsed());
-        vdfs.add_comment(match comment_override {
-            Some(s) => Some(s),
-            None => Some(script.comment),
-        })
-        .save_to_file(match output_file_override {
-            Some(o) => o,
-            None => &script.file_path,
-        })?;
-        Ok(())
+        if self.fs.add_child(manifest).is_some() {
+            eprintln!(
+                "[WARN] Replacing {} from the source directory with the generated listing.",
+                MANIFEST_NAME
+            );
+        }
     }
 
-    fn build_catalog(&mut self) {
+    fn build_catalog(&mut self, cancel: Option<&AtomicBool>) -> Result<()> {
         let mut queue = VecDeque::new();
         queue.push_back((-1, &self.fs));
+        // Indices of the chunks by the digest of their contents, only used with `dedup`
+        let mut stored: HashMap<FileDigest, Vec<usize>> = HashMap::new();
+        let reads_contents = self.dedup || self.crc_in_attributes;
+        let mut digests = if reads_contents && self.parallel_read {
+            digest_files(&self.fs, cancel)
+        } else {
+            HashMap::new()
+        };
+        let mut data_len = self.data_len();
+        let encoding = self.header.encoding;
+        // Catalog index of the first child of every directory by the index of the directory,
+        // -1 for the root. Siblings are pushed one after the other, so the first one starts the run.
+        let mut first_child: HashMap<i32, u32> = HashMap::new();
 
         let mut index = -1;
         while !queue.is_empty() {
@@ -316,13 +1173,25 @@ impl Vdfs {
             match node {
                 FileSystemNode::Directory {
                     name,
-                    path: _,
+                    path,
                     is_last,
                     children,
                     level: _,
                 } => {
+                    if let Some((dir, file)) = find_dir_file_clash(children) {
+                        bail!(
+                            "The directory {} and the file {} end up with the same name in the archive.",
+                            dir.display(),
+                            file.display()
+                        );
+                    }
+
                     if node != &self.fs {
-                        let mut e = VDFSCatalogEntry::new(name);
+                        let mut e = VDFSCatalogEntry::new(name, encoding)
+                            .with_context(|| format!("Cannot pack {}", path.display()))?;
+                        if self.preserve_case {
+                            e.keep_case(encoding);
+                        }
                         e.is_dir = true;
                         e.typ |= EntryType::Dir as u32;
                         if *is_last {
@@ -343,150 +1212,1114 @@ impl Vdfs {
                 FileSystemNode::File {
                     name,
                     path,
+                    contents,
                     is_last,
                     level: _,
                 } => {
-                    let mut e = VDFSCatalogEntry::new_sized(
-                        name,
-                        match fs::metadata(path) {
+                    if cancel.is_some_and(|c| c.load(Ordering::Relaxed)) {
+                        return Err(Cancelled.into());
+                    }
+                    let size = match contents {
+                        Some(c) => c.len() as u64,
+                        None => match fs::metadata(path) {
                             Ok(m) => m.len(),
-                            Err(e) => {
-                                eprintln!("ERROR: {}", e);
-                                exit(420);
-                            }
+                            Err(e) => return Err(read_error(path, e)),
                         },
-                    );
+                    };
+                    // Checked before reading so a huge file is neither loaded nor wrapped around
+                    if size > u32::MAX as u64 {
+                        bail!(
+                            "{} is {} bytes, files in an archive cannot be larger than 4 GiB.",
+                            path.display(),
+                            size
+                        );
+                    }
+                    let mut e = VDFSCatalogEntry::new_sized(name, size, encoding)
+                        .with_context(|| format!("Cannot pack {}", path.display()))?;
+                    if self.preserve_case {
+                        e.keep_case(encoding);
+                    }
                     e.is_dir = false;
                     e.parent_id = par;
 
                     if *is_last {
-                        e.typ = EntryType::LastFile as u32;
+                        e.typ |= EntryType::LastFile as u32;
                     }
-                    self.catalog_dirs.push(e);
-                    match fs::read(path) {
-                        Ok(mut d) => self.data.append(&mut d),
-                        Err(e) => {
-                            eprintln!("ERROR: {}", e);
-                            exit(69);
+                    // Files are only read here when their contents matter for the catalog,
+                    // otherwise they are first read while writing
+                    let digest = match contents {
+                        Some(c) if reads_contents => Some(FileDigest::of(c)),
+                        Some(_) => None,
+                        None if reads_contents => {
+                            let digest = digests
+                                .remove(path)
+                                .unwrap_or_else(|| FileDigest::of_file(path));
+                            match digest {
+                                Ok(d) => Some(d),
+                                Err(e) => return Err(read_error(path, e)),
+                            }
+                        }
+                        None => match File::open(path) {
+                            Ok(_) => None,
+                            Err(e) => return Err(read_error(path, e)),
+                        },
+                    };
+                    if digest.is_some_and(|d| d.len != size) {
+                        bail!("{} changed while it was packed.", path.display());
+                    }
+
+                    if let (true, Some(digest)) = (self.crc_in_attributes, digest) {
+                        e.attributes = digest.crc;
+                    }
+                    if self.preserve_attributes
+                        && contents.is_none()
+                        && fs::metadata(path).is_ok_and(|m| m.permissions().readonly())
+                    {
+                        e.attributes |= ATTRIBUTE_READONLY;
+                    }
+
+                    // Where the bytes start in the data, made absolute once the catalog size is known
+                    let shared = match digest {
+                        Some(digest) if self.dedup => {
+                            let candidates = stored.entry(digest).or_default();
+                            // Only read again when the digests match, to rule out a collision
+                            let shared = if candidates.is_empty() {
+                                None
+                            } else {
+                                let bytes = match contents {
+                                    Some(c) => Cow::Borrowed(c.as_slice()),
+                                    None => match fs::read(path) {
+                                        Ok(d) => Cow::Owned(d),
+                                        Err(e) => return Err(read_error(path, e)),
+                                    },
+                                };
+                                candidates
+                                    .iter()
+                                    .copied()
+                                    .find(|&i| self.chunk_data(i).is_some_and(|c| c == bytes))
+                            };
+                            if shared.is_none() {
+                                candidates.push(self.chunks.len());
+                            }
+                            shared.map(|i| self.chunks[i].0)
+                        }
+                        _ => None,
+                    };
+                    e.next_index = match shared {
+                        Some(start) => start as u32,
+                        None => {
+                            // The catalog so far already comes before the data, it only grows
+                            let data_start = HEADER_SIZE as u64
+                                + self.catalog_gap.len() as u64
+                                + (self.catalog_dirs.len() as u64 + 1) * CATALOG_ENTRY_SIZE as u64;
+                            if data_start + data_len + size > u32::MAX as u64 {
+                                bail!(
+                                    "The archive outgrows the 4 GiB its offsets can address at {}.",
+                                    path.display()
+                                );
+                            }
+                            let chunk = match contents {
+                                Some(c) => DataChunk::Bytes(c.clone()),
+                                None => DataChunk::File {
+                                    path: path.clone(),
+                                    size: size as u32,
+                                },
+                            };
+                            self.chunks.push((data_len, chunk));
+                            data_len += size;
+                            (data_len - size) as u32
                         }
+                    };
+                    self.catalog_dirs.push(e);
+                    if let Some(progress) = &self.progress {
+                        progress.file_done(&path.to_string_lossy(), size);
                     }
                 }
             }
+            // Every node but the root got the entry at `index`
+            if index >= 0 {
+                first_child.entry(par).or_insert(index as u32);
+            }
             index += 1;
         }
 
         let mut queue = VecDeque::new();
-        queue.push_back(&self.fs);
+        // Only directories have a first child to look up
+        if self.catalog_dirs.iter().any(|e| e.is_dir) {
+            queue.push_back(&self.fs);
+        }
 
         let mut i = -1;
         while !queue.is_empty() {
             let node = queue.pop_front().unwrap();
 
-            match node {
-                FileSystemNode::Directory {
-                    name: _,
-                    path: _,
-                    children,
-                    is_last: _,
-                    level: _,
-                } => {
-                    if node != &self.fs {
-                        let _id = self.find_index(i as u32);
-                        self.catalog_dirs[i as usize].next_index = _id;
-
-                        for child in children {
-                            queue.push_back(child);
-                        }
+            if let FileSystemNode::Directory {
+                name: _,
+                path: _,
+                children,
+                is_last: _,
+                level: _,
+            } = node
+            {
+                if node != &self.fs {
+                    // An empty directory points past the catalog, so no entry is read as its child
+                    let _id = if children.is_empty() {
+                        self.catalog_dirs.len() as u32
                     } else {
-                        for child in children {
-                            queue.push_back(child);
-                        }
+                        first_child[&i]
+                    };
+                    self.catalog_dirs[i as usize].next_index = _id;
+
+                    for child in children {
+                        queue.push_back(child);
+                    }
+                } else {
+                    for child in children {
+                        queue.push_back(child);
                     }
                 }
-                _ => {}
             }
             i += 1;
         }
 
-        let final_num = self.catalog_dirs.len(); // + self.catalog_files.len();
-        self.header.catalog_offset = 296_u32;
+        let final_num = self.catalog_dirs.len();
+        let data_start = HEADER_SIZE as u64
+            + self.catalog_gap.len() as u64
+            + final_num as u64 * CATALOG_ENTRY_SIZE as u64;
+        if data_start + data_len > u32::MAX as u64 {
+            bail!(
+                "The archive would be {} bytes, its offsets cannot address more than 4 GiB.",
+                data_start + data_len
+            );
+        }
+        self.header.catalog_offset = HEADER_SIZE + self.catalog_gap.len() as u32;
         self.header.num_files = final_num as u32;
-        self.header.num_entries = self
-            .catalog_dirs
-            .iter()
-            .filter(|f| f.typ == 0 || f.typ == EntryType::LastFile as u32)
-            .count() as u32; // self.catalog_files.len() as u32;
+        self.header.num_entries = self.catalog_dirs.iter().filter(|f| !f.is_dir).count() as u32;
 
         self.catalog_dirs
             .iter_mut()
-            .filter(|f| f.typ == 0 || f.typ == EntryType::LastFile as u32)
+            .filter(|f| !f.is_dir)
             .for_each(|f| {
-                f.next_index =
-                    self.header.catalog_offset + self.header.num_files * 80 + self.curr_pos;
-                self.curr_pos += f.size;
+                f.next_index +=
+                    self.header.catalog_offset + self.header.num_files * CATALOG_ENTRY_SIZE;
             });
+        self.curr_pos = data_len as u32;
+
+        self.index_paths();
+        Ok(())
     }
 
-    fn find_index(&self, level: u32) -> u32 {
-        match self
-            .catalog_dirs
+    /// Caches the logical path of every entry so lookups don't have to walk `parent_id` chains.
+    fn index_paths(&mut self) {
+        let mut paths: Vec<Option<String>> = vec![None; self.catalog_dirs.len()];
+
+        for i in 0..self.catalog_dirs.len() {
+            // Collect the ancestors up to the first one whose path is already known
+            let mut chain = vec![i];
+            let mut parent = self.catalog_dirs[i].parent_id;
+            while parent >= 0
+                && paths[parent as usize].is_none()
+                && chain.len() <= self.catalog_dirs.len()
+            {
+                chain.push(parent as usize);
+                parent = self.catalog_dirs[parent as usize].parent_id;
+            }
+
+            let mut path = match parent {
+                -1 => String::new(),
+                p => paths[p as usize].clone().unwrap_or_default(),
+            };
+            for j in chain.into_iter().rev() {
+                if paths[j].is_some() {
+                    continue;
+                }
+                if !path.is_empty() {
+                    path.push('/');
+                }
+                path.push_str(&self.catalog_dirs[j].name_utf8);
+                paths[j] = Some(path.clone());
+            }
+        }
+
+        self.entry_paths = paths.into_iter().map(Option::unwrap_or_default).collect();
+        self.path_index = self
+            .entry_paths
             .iter()
-            .position(|item| item.parent_id as u32 == level)
-        {
-            Some(i) => i as u32,
-            None => 0_u32,
+            .enumerate()
+            .map(|(i, p)| (p.to_ascii_uppercase(), i))
+            .collect();
+    }
+
+    /// File entries sorted by where their data starts, for reading the archive sequentially.
+    pub fn entries_by_offset(&self) -> Vec<&VDFSCatalogEntry> {
+        let mut files: Vec<_> = self.catalog_dirs.iter().filter(|e| !e.is_dir).collect();
+        files.sort_by_key(|e| e.next_index);
+        files
+    }
+
+    /// Every catalog entry together with its logical path, in catalog order.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &VDFSCatalogEntry)> {
+        self.entry_paths
+            .iter()
+            .map(String::as_str)
+            .zip(self.catalog_dirs.iter())
+    }
+
+    /// Every entry of the archive root with the number and total size of the files in it,
+    /// in catalog order. A file in the root counts as itself.
+    pub fn top_level_summary(&self) -> Vec<TopLevelSummary> {
+        let mut summary: Vec<TopLevelSummary> = Vec::new();
+        let mut groups = HashMap::new();
+        for (path, entry) in self.entries() {
+            let top = path.split('/').next().unwrap_or(path);
+            let i = *groups.entry(top.to_ascii_uppercase()).or_insert_with(|| {
+                summary.push(TopLevelSummary {
+                    name: top.to_string(),
+                    is_dir: entry.is_dir,
+                    files: 0,
+                    bytes: 0,
+                });
+                summary.len() - 1
+            });
+            if !entry.is_dir {
+                summary[i].files += 1;
+                summary[i].bytes += entry.size as u64;
+            }
+        }
+        summary
+    }
+
+    /// Writes the directories and files as a tree in catalog order, every file with its size.
+    pub fn write_tree(&self, w: &mut impl io::Write) -> io::Result<()> {
+        // Archives put together from several sources have a root without a name
+        let root = match self.fs.name() {
+            "" => "/",
+            name => name,
+        };
+        let mut builder = ptree::TreeBuilder::new(root.to_string());
+        self.add_tree_items(&mut builder, &self.fs, "");
+        ptree::write_tree(&builder.build(), w)
+    }
+
+    /// Writes what `save_to_file` would produce: the number of entries, the size of the data
+    /// and of the whole archive, followed by the tree from `write_tree`.
+    pub fn write_plan(&self, w: &mut impl io::Write) -> io::Result<()> {
+        writeln!(
+            w,
+            "Entries: {} ({} files)",
+            self.header.num_files, self.header.num_entries
+        )?;
+        writeln!(w, "Data: {} bytes", self.header.size)?;
+        writeln!(w, "Archive: {} bytes", self.estimated_file_size())?;
+        self.write_tree(w)
+    }
+
+    fn add_tree_items(
+        &self,
+        builder: &mut ptree::TreeBuilder,
+        node: &FileSystemNode,
+        prefix: &str,
+    ) {
+        for child in node.children() {
+            let path = format!("{}{}", prefix, child.name());
+            if child.is_dir() {
+                builder.begin_child(format!("{}/", child.name()));
+                self.add_tree_items(builder, child, &format!("{}/", path));
+                builder.end_child();
+            } else {
+                let size = self
+                    .find_entry(&path)
+                    .map_or(0, |i| self.catalog_dirs[i].size);
+                builder.add_empty_child(format!("{} ({} bytes)", child.name(), size));
+            }
         }
     }
 
-    // This could be done elegantly with serde, but I don't know how to use it :kekw:
     pub fn save_to_file(&self, output_file: &PathBuf) -> Result<(), std::io::Error> {
         let time = Instant::now();
-        println!("[INFO] Writing {}", output_file.display());
-        let file = File::create(output_file)?;
+        eprintln!("[INFO] Writing {}", output_file.display());
+
+        let estimated_size = self.estimated_file_size();
+        if estimated_size > FAT32_MAX_FILE_SIZE {
+            eprintln!(
+                "[WARN] The archive will be {} bytes, FAT32 formatted targets cannot store files over 4 GiB.",
+                estimated_size
+            );
+        }
 
+        let file = File::create(output_file)?;
         let mut buf_writer = BufWriter::new(file);
+        self.write_to(&mut buf_writer)?;
+        buf_writer.flush()?;
+        eprintln!("[INFO] Done: {:.2?}", time.elapsed());
+        Ok(())
+    }
+
+    /// Writes the whole archive to `w`, like to a socket or a `Vec<u8>`. It is written in many
+    /// small pieces, so unbuffered writers like a `File` are best wrapped in a `BufWriter`.
+    /// The source files of a built archive are read while writing.
+    // This could be done elegantly with serde, but I don't know how to use it :kekw:
+    pub fn write_to<W: Write + ?Sized>(&self, w: &mut W) -> Result<(), std::io::Error> {
+        let bytes = match self.endianness {
+            Endianness::Little => u32::to_le_bytes,
+            Endianness::Big => {
+                eprintln!("[WARN] Writing the numbers big-endian for debugging, no loader can read this archive.");
+                u32::to_be_bytes
+            }
+        };
 
-        buf_writer.write_all(&self.header.comment)?;
-        buf_writer.write_all(&self.header.signature)?;
-        buf_writer.write_all(&self.header.num_files.to_le_bytes())?;
-        buf_writer.write_all(&self.header.num_entries.to_le_bytes())?;
-        buf_writer.write_all(&self.header.timestamp.to_le_bytes())?;
-        buf_writer.write_all(&self.header.size.to_le_bytes())?;
-        buf_writer.write_all(&self.header.catalog_offset.to_le_bytes())?;
-        buf_writer.write_all(&self.header.version.to_le_bytes())?;
+        w.write_all(&self.header.comment)?;
+        w.write_all(&self.header.signature)?;
+        w.write_all(&bytes(self.header.num_files))?;
+        w.write_all(&bytes(self.header.num_entries))?;
+        w.write_all(&bytes(self.header.timestamp))?;
+        w.write_all(&bytes(self.header.size))?;
+        w.write_all(&bytes(self.header.catalog_offset))?;
+        w.write_all(&bytes(self.header.version))?;
+        w.write_all(&self.catalog_gap)?;
 
         for c in &self.catalog_dirs {
-            buf_writer.write_all(&c.name)?;
-            buf_writer.write_all(&c.next_index.to_le_bytes())?;
-            buf_writer.write_all(&c.size.to_le_bytes())?;
-            buf_writer.write_all(&c.typ.to_le_bytes())?;
-            buf_writer.write_all(&c.attributes.to_le_bytes())?;
+            w.write_all(&c.name)?;
+            w.write_all(&bytes(c.next_index))?;
+            w.write_all(&bytes(c.size))?;
+            w.write_all(&bytes(c.typ))?;
+            w.write_all(&bytes(c.attributes))?;
         }
 
-        buf_writer.write_all(&self.data)?;
+        w.write_all(&self.data)?;
+        let total = self.data_len();
+        for (start, chunk) in &self.chunks {
+            match chunk {
+                DataChunk::Bytes(bytes) => w.write_all(bytes)?,
+                DataChunk::File { path, size } => copy_source(path, *size, w)?,
+                DataChunk::Zeros(size) => {
+                    io::copy(&mut io::repeat(0).take(*size), w)?;
+                }
+            }
+            if let Some(progress) = &self.progress {
+                progress.bytes_written(start + chunk.len(), total);
+            }
+        }
+        Ok(())
+    }
 
-        buf_writer.flush()?;
-        println!("[INFO] Done: {:.2?}", time.elapsed());
+    /// Reads `output_file` back and compares its header, entries and file contents to this archive,
+    /// catching anything that got lost or mangled while writing.
+    pub fn verify_written(&self, output_file: &PathBuf) -> Result<()> {
+        eprintln!("[INFO] Verifying {}", output_file.display());
+        let options = ReadOptions {
+            encoding: self.header.encoding,
+            signature: Some(self.header.signature),
+            version: Some(self.header.version),
+            ..Default::default()
+        };
+        let written = Vdfs::from_file_with(output_file, &options)?;
+
+        let mut differences = Vec::new();
+        if written.header.num_files != self.header.num_files
+            || written.header.num_entries != self.header.num_entries
+            || written.header.size != self.header.size
+        {
+            differences.push("the header counts differ".to_string());
+        }
+
+        let packed: Vec<_> = self.entries().collect();
+        let read: Vec<_> = written.entries().collect();
+        for (i, (path, e)) in packed.iter().enumerate() {
+            let Some((read_path, r)) = read.get(i) else {
+                differences.push(format!("{} is missing", path));
+                continue;
+            };
+            if !path.eq_ignore_ascii_case(read_path) || e.is_dir != r.is_dir {
+                differences.push(format!("{} was read back as {}", path, read_path));
+            } else if e.size != r.size {
+                differences.push(format!(
+                    "{} has {} bytes instead of {}",
+                    path, r.size, e.size
+                ));
+            } else if !e.is_dir && self.entry_data(e) != written.entry_data(r) {
+                differences.push(format!("{} has different contents", path));
+            }
+        }
+        for (path, _) in read.iter().skip(packed.len()) {
+            differences.push(format!("{} was not packed", path));
+        }
+
+        for difference in &differences {
+            eprintln!("[ERROR] {}", difference);
+        }
+        if !differences.is_empty() {
+            bail!(
+                "{} does not match what was packed ({} differences).",
+                output_file.display(),
+                differences.len()
+            );
+        }
+        eprintln!("[INFO] {} matches what was packed.", output_file.display());
+        Ok(())
+    }
+
+    /// Where the data of a file entry starts relative to the end of the catalog, `None` when
+    /// it points in front of it. Counted in u64 so a forged header cannot overflow it.
+    fn data_offset(&self, entry: &VDFSCatalogEntry) -> Option<usize> {
+        let data_start = (self.header.num_files as u64)
+            .checked_mul(CATALOG_ENTRY_SIZE as u64)?
+            .checked_add(self.header.catalog_offset as u64)?;
+        usize::try_from((entry.next_index as u64).checked_sub(data_start)?).ok()
+    }
+
+    /// The bytes of a file entry, `None` if the entry points outside of the data.
+    /// Files of a built archive are read from their source again.
+    fn entry_data(&self, entry: &VDFSCatalogEntry) -> Option<Cow<'_, [u8]>> {
+        let start = self.data_offset(entry)?;
+        if self.chunks.is_empty() {
+            return self
+                .data
+                .get(start..start.checked_add(entry.size as usize)?)
+                .map(Cow::Borrowed);
+        }
+        // Every file starts a chunk of its own, shared contents included
+        let i = self
+            .chunks
+            .binary_search_by_key(&(start as u64), |(start, _)| *start)
+            .ok()?;
+        self.chunk_data(i)
+            .filter(|data| data.len() == entry.size as usize)
+    }
+
+    /// Like `entry_data`, but only for bytes held in memory. Files of a built archive that are
+    /// read from their source give `None` without being read.
+    fn entry_slice(&self, entry: &VDFSCatalogEntry) -> Option<&[u8]> {
+        let start = self.data_offset(entry)?;
+        if self.chunks.is_empty() {
+            return self
+                .data
+                .get(start..start.checked_add(entry.size as usize)?);
+        }
+        let i = self
+            .chunks
+            .binary_search_by_key(&(start as u64), |(start, _)| *start)
+            .ok()?;
+        match &self.chunks[i].1 {
+            DataChunk::Bytes(bytes) if bytes.len() == entry.size as usize => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// The contents of the chunk at `i`, `None` for padding and sources that cannot be read.
+    fn chunk_data(&self, i: usize) -> Option<Cow<'_, [u8]>> {
+        match &self.chunks[i].1 {
+            DataChunk::Bytes(bytes) => Some(Cow::Borrowed(bytes)),
+            DataChunk::File { path, .. } => fs::read(path).ok().map(Cow::Owned),
+            DataChunk::Zeros(_) => None,
+        }
+    }
+
+    /// Bytes of file data, those of a read archive or everything the chunks of a built one add up to.
+    fn data_len(&self) -> u64 {
+        match self.chunks.last() {
+            Some((start, chunk)) => start + chunk.len(),
+            None => self.data.len() as u64,
+        }
+    }
+
+    /// Size of the written archive, counted in u64 so it does not wrap like the u32 header fields.
+    fn estimated_file_size(&self) -> u64 {
+        HEADER_SIZE as u64
+            + self.catalog_gap.len() as u64
+            + self.catalog_dirs.len() as u64 * CATALOG_ENTRY_SIZE as u64
+            + self.data_len()
+    }
+
+    /// Pads the data to `reserve` bytes after `calculate_data_size` recorded the size in use.
+    fn reserve_data(&mut self, reserve: u64) -> Result<()> {
+        let used = self.data_len();
+        if used > reserve {
+            bail!(
+                "The files take {} bytes, more than the {} bytes reserved.",
+                used,
+                reserve
+            );
+        }
+        if reserve > used {
+            self.chunks.push((used, DataChunk::Zeros(reserve - used)));
+        }
+        self.curr_pos = reserve as u32;
+        Ok(())
+    }
+
+    /// Sets the data size of the header and pads the data up to the reserved size.
+    fn lay_out_data(&mut self) -> Result<()> {
+        self.calculate_data_size();
+        if let Some(reserve) = self.rebuild.reserve {
+            self.reserve_data(reserve)?;
+        }
         Ok(())
     }
 
     fn calculate_data_size(&mut self) {
-        self.header.size = self.catalog_dirs.iter().map(|entry| entry.size).sum();
+        // Smaller than the sum of the file sizes when contents are shared
+        self.header.size = self.data_len() as u32;
     }
 
     pub fn add_comment(mut self, cmnt: Option<&str>) -> Self {
-        self.header.comment(match cmnt {
-            Some(c) => c,
-            None => "",
-        });
+        self.header.comment(cmnt.unwrap_or_default());
         self
     }
 
+    /// Uses the DOS time `timestamp` for the header instead of the moment the archive was built.
+    pub fn with_timestamp(mut self, timestamp: u32) -> Self {
+        self.header.timestamp = timestamp;
+        self
+    }
+
+    /// Writes `version` into the header instead of 80, for engine forks that expect another one.
+    /// The catalog layout stays the same, loaders of the original engine warn about or reject it.
+    pub fn with_version(mut self, version: u32) -> Self {
+        self.header.version = version;
+        self
+    }
+
+    /// Writes `signature` into the header instead of `PSVDSC_V2.00`, fails unless it is exactly
+    /// `SIGNATURE_SIZE` bytes. Reading the archive back needs `ReadOptions::signature`.
+    pub fn with_signature(mut self, signature: &[u8]) -> Result<Self> {
+        let Ok(signature) = signature.try_into() else {
+            bail!(
+                "The signature has {} bytes, it has to be {}.",
+                signature.len(),
+                SIGNATURE_SIZE
+            );
+        };
+        self.header.signature = signature;
+        Ok(self)
+    }
+
+    /// Overwrites header fields after the catalog has been built, without any checks.
+    /// Only meant for producing deliberately inconsistent archives to test other parsers.
+    #[doc(hidden)]
+    pub fn with_raw_header_overrides(mut self, overrides: RawHeaderOverrides) -> Self {
+        if let Some(num_files) = overrides.num_files {
+            self.header.num_files = num_files;
+        }
+        if let Some(num_entries) = overrides.num_entries {
+            self.header.num_entries = num_entries;
+        }
+        if let Some(size) = overrides.size {
+            self.header.size = size;
+        }
+        if let Some(catalog_offset) = overrides.catalog_offset {
+            self.header.catalog_offset = catalog_offset;
+        }
+        if let Some(version) = overrides.version {
+            self.header.version = version;
+        }
+        self
+    }
+
+    /// Finds the catalog index of a logical path like `MESHES/LEVEL/ROCK.MRM`,
+    /// ignoring case and accepting both `/` and `\\` as separators.
+    fn find_entry(&self, path: &str) -> Option<usize> {
+        let key = path
+            .split(['/', '\\'])
+            .filter(|c| !c.is_empty())
+            .collect::<Vec<_>>()
+            .join("/")
+            .to_ascii_uppercase();
+        self.path_index.get(&key).copied()
+    }
+
+    /// The header with its fields decoded, the comment in the archive's encoding.
+    pub fn header_info(&self) -> HeaderInfo {
+        let header = &self.header;
+        HeaderInfo {
+            comment: header.comment_lossy(),
+            signature: String::from_utf8_lossy(&header.signature)
+                .trim_end_matches(['\n', '\r', '\0'])
+                .to_string(),
+            num_files: header.num_files,
+            num_entries: header.num_entries,
+            timestamp: dos_time_to_datetime(header.timestamp),
+            size: header.size,
+            catalog_offset: header.catalog_offset,
+            version: header.version,
+        }
+    }
+
+    /// Whether the archive has a file or directory at the logical path `path`,
+    /// matched like `find_entry`.
+    pub fn contains(&self, path: &str) -> bool {
+        self.find_entry(path).is_some()
+    }
+
+    /// Where the bytes of the file at the logical path `path` live in the archive,
+    /// as offset from the start of the header and size. `None` for directories and unknown paths.
+    pub fn offset_of(&self, path: &str) -> Option<(u32, u32)> {
+        let entry = &self.catalog_dirs[self.find_entry(path)?];
+        if entry.is_dir {
+            return None;
+        }
+        Some((entry.next_index, entry.size))
+    }
+
+    /// Renames the entry at the logical path `from` to the name `to` in place.
+    /// Names are fixed-width so offsets stay valid, but the entry keeps its position
+    /// among its siblings even if the new name would sort differently.
+    pub fn rename_entry(&mut self, from: &str, to: &str) -> Result<()> {
+        let Some(index) = self.find_entry(from) else {
+            bail!("{} is not in the archive.", from);
+        };
+        if to.is_empty() || to.contains(['/', '\\']) {
+            bail!("{} is not a valid entry name.", to);
+        }
+        if to.len() > 64 {
+            bail!("{} is longer than 64 bytes.", to);
+        }
+
+        let parent = self.catalog_dirs[index].parent_id;
+        if self.catalog_dirs.iter().enumerate().any(|(i, e)| {
+            i != index && e.parent_id == parent && e.name_utf8.eq_ignore_ascii_case(to)
+        }) {
+            bail!(
+                "Cannot rename {} to {}, the name is already taken.",
+                from,
+                to
+            );
+        }
+
+        self.catalog_dirs[index].set_name(to, self.header.encoding)?;
+        if self.preserve_case {
+            self.catalog_dirs[index].keep_case(self.header.encoding);
+        }
+        self.index_paths();
+        Ok(())
+    }
+
+    /// Adds a file with the given bytes to the archive root, replacing an entry of the same name.
+    /// Meant for generated content that never exists on disk, the catalog is rebuilt afterwards
+    /// and checked, sorted and padded like the build did. The archive is left as it was when that fails.
+    pub fn add_raw_entry(&mut self, name: &str, data: Vec<u8>) -> Result<()> {
+        if name.is_empty() || name.contains(['/', '\\']) {
+            bail!("{} is not a valid entry name.", name);
+        }
+        if name.len() > 64 {
+            bail!("{} is longer than 64 bytes.", name);
+        }
+        if let FileSystemNode::Directory { children, .. } = &self.fs {
+            // The files of opened archives have no source to read them from when rebuilding
+            if self.opened {
+                bail!("Entries can only be added to archives built from a directory or a script.");
+            }
+            if children.iter().any(|c| {
+                matches!(c, FileSystemNode::Directory { name: n, .. } if n.eq_ignore_ascii_case(name))
+            }) {
+                bail!("{} is already a directory in the archive.", name);
+            }
+        }
+
+        let previous = self.fs.clone();
+        self.fs.add_child(FileSystemNode::File {
+            name: name.to_string(),
+            path: PathBuf::from(name),
+            contents: Some(data),
+            is_last: false,
+            level: 0,
+        });
+        if let Some(dirs_first) = self.rebuild.dirs_first {
+            self.fs
+                .sort_children_by_stored_name(self.header.encoding, dirs_first);
+        }
+        if let Err(err) = self.rebuild_catalog() {
+            self.fs = previous;
+            self.rebuild_catalog()?;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Builds the catalog and data of the tree again, checked and padded like the build did.
+    fn rebuild_catalog(&mut self) -> Result<()> {
+        self.catalog_dirs.clear();
+        self.data.clear();
+        self.chunks.clear();
+        self.curr_pos = 0;
+        self.build_catalog(None)?;
+        self.check_catalog()?;
+        self.lay_out_data()?;
+        self.count_stats();
+        Ok(())
+    }
+
     // pub fn set_comment(&mut self, cmnt: &str) {
     //     self.header.comment(cmnt);
     // }
 }
 
+/// What building the catalog needs to know about the contents of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FileDigest {
+    len: u64,
+    crc: u32,
+}
+
+impl FileDigest {
+    fn of(bytes: &[u8]) -> Self {
+        FileDigest {
+            len: bytes.len() as u64,
+            crc: crc32fast::hash(bytes),
+        }
+    }
+
+    /// Reads the file at `path` piece by piece, so it never has to fit into memory as a whole.
+    fn of_file(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut hasher = crc32fast::Hasher::new();
+        let mut buf = vec![0; 64 * 1024];
+        let mut len = 0;
+        loop {
+            let read = match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            hasher.update(&buf[..read]);
+            len += read as u64;
+        }
+        Ok(FileDigest {
+            len,
+            crc: hasher.finalize(),
+        })
+    }
+}
+
+/// The digests of all files below `fs` that are read from disk, taken on all cores.
+/// Errors are kept so they surface in catalog order, like when the files are read one by one.
+fn digest_files(
+    fs: &FileSystemNode,
+    cancel: Option<&AtomicBool>,
+) -> HashMap<PathBuf, io::Result<FileDigest>> {
+    use rayon::prelude::*;
+
+    let mut paths = Vec::new();
+    let mut queue = vec![fs];
+    while let Some(node) = queue.pop() {
+        match node {
+            FileSystemNode::Directory { children, .. } => queue.extend(children),
+            FileSystemNode::File {
+                path,
+                contents: None,
+                ..
+            } => paths.push(path),
+            FileSystemNode::File { .. } => {}
+        }
+    }
+    paths
+        .into_par_iter()
+        // A cancelled build stops before reading the files that are left
+        .filter(|_| !cancel.is_some_and(|c| c.load(Ordering::Relaxed)))
+        .map(|path| (path.clone(), FileDigest::of_file(path)))
+        .collect()
+}
+
+/// Copies the `size` bytes of the source file at `path` into the archive, failing when
+/// the file no longer has the size the catalog was built with.
+fn copy_source(path: &Path, size: u32, w: &mut (impl Write + ?Sized)) -> io::Result<()> {
+    let with_path = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path.display(), e));
+    let file = File::open(path).map_err(with_path)?;
+    let len = file.metadata().map_err(with_path)?.len();
+    if len != size as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} changed after it was packed, it has {} bytes instead of {}.",
+                path.display(),
+                len,
+                size
+            ),
+        ));
+    }
+    let copied = io::copy(&mut file.take(size as u64), w).map_err(with_path)?;
+    if copied != size as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!(
+                "{} ended after {} of {} bytes.",
+                path.display(),
+                copied,
+                size
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Collects the files matched by the include globs of a script into a tree.
+fn script_tree(
+    script: &VdfsScript,
+    base_dir_override: &Option<PathBuf>,
+    tree_filter: &mut TreeFilter,
+) -> Result<FileSystemNode> {
+    if script.base_dir.as_os_str().is_empty() && base_dir_override.is_none() {
+        bail!("Empty base directory path in script file and no override was provided.");
+    }
+
+    let base_dir = &resolve_base_dir(match base_dir_override {
+        Some(pb) => pb,
+        None => &script.base_dir,
+    })?;
+    if !base_dir.is_dir() {
+        bail!("The base directory {} does not exist.", base_dir.display());
+    }
+
+    let mut path_filter: Vec<Vec<String>> = Vec::new();
+    // The first glob that matched each path, for explaining the selection
+    let mut included = HashMap::new();
+    for g in &script.file_include_globs {
+        let Some(paths) = glob_relative(base_dir, g) else {
+            continue;
+        };
+        for relative in paths {
+            path_filter.push(
+                relative
+                    .iter()
+                    .map(|component| component.to_string_lossy().to_string())
+                    .collect(),
+            );
+            included.entry(relative).or_insert(g.as_ref());
+        }
+    }
+    let mut excluded = HashMap::new();
+    for g in &script.file_exclude_globs {
+        for relative in glob_relative(base_dir, g).unwrap_or_default() {
+            excluded.entry(relative).or_insert(g.as_ref());
+        }
+    }
+
+    let mut tree = build_file_system_tree_filtered(base_dir, -1, &path_filter, tree_filter)?;
+    if !excluded.is_empty() {
+        let removed = tree.retain_files(&|path| {
+            let relative = relative_to_base(path, base_dir).unwrap_or_default();
+            !excluded.contains_key(&relative)
+                || (script.precedence == Precedence::Include && included.contains_key(&relative))
+        });
+        eprintln!(
+            "[INFO] Left out {} files matching file_exclude_globs.",
+            removed
+        );
+    }
+    if tree_filter.explains() {
+        explain_selection(&tree, base_dir, &included, &excluded);
+    }
+    for mapping in &script.file_mapping {
+        let source = base_dir.join(&mapping.source);
+        if !source.is_file() {
+            eprintln!(
+                "[WARN] {} of the file mapping is not a file, skipping it.",
+                source.display()
+            );
+            continue;
+        }
+        let Some(node) = builder::nest(&mapping.path, |name| FileSystemNode::File {
+            name,
+            path: source.clone(),
+            contents: None,
+            is_last: false,
+            level: 0,
+        }) else {
+            continue;
+        };
+        for replaced in tree.merge_child(node) {
+            eprintln!(
+                "[WARN] {} was replaced by {} from the file mapping.",
+                replaced.path().display(),
+                source.display()
+            );
+        }
+        if tree_filter.explains() {
+            eprintln!(
+                "[INFO] {}: placed by the file mapping from {}",
+                mapping.path,
+                mapping.source.display()
+            );
+        }
+    }
+    Ok(tree)
+}
+
+/// Prints the glob that selected every file of `tree` and the exclude glob that left out
+/// the others, paths are relative to `base_dir`.
+fn explain_selection(
+    tree: &FileSystemNode,
+    base_dir: &Path,
+    included: &HashMap<PathBuf, &str>,
+    excluded: &HashMap<PathBuf, &str>,
+) {
+    let mut packed = HashSet::new();
+    let mut queue = VecDeque::from([tree]);
+    while let Some(node) = queue.pop_front() {
+        queue.extend(node.children());
+        if node.is_dir() {
+            continue;
+        }
+        let relative = relative_to_base(node.path(), base_dir).unwrap_or_default();
+        let why = match (included.get(&relative), excluded.get(&relative)) {
+            (Some(include), Some(exclude)) => format!(
+                "included by {}, kept despite the exclude glob {} (precedence: include)",
+                include, exclude
+            ),
+            (Some(include), None) => format!("included by {}, no exclude glob matches", include),
+            // The filter matches names level by level, so a file can come along with a
+            // file of the same name another glob matched in a different directory
+            (None, _) => "matched by no glob, selected along with a file of the same name".into(),
+        };
+        eprintln!("[INFO] {}: {}", relative.display(), why);
+        packed.insert(relative);
+    }
+
+    let mut left_out: Vec<_> = excluded
+        .iter()
+        .filter(|(path, _)| included.contains_key(*path) && !packed.contains(*path))
+        .collect();
+    left_out.sort();
+    for (path, exclude) in left_out {
+        eprintln!(
+            "[INFO] {}: included by {}, left out by the exclude glob {}",
+            path.display(),
+            included[path],
+            exclude
+        );
+    }
+}
+
+/// The paths below `base_dir` the glob `g` matches, relative to it and ignoring case.
+/// `{a,b}` groups are expanded first, a path matched by several alternatives is listed once.
+/// `None` when `g` is not a valid glob.
+fn glob_relative(base_dir: &Path, g: &str) -> Option<Vec<PathBuf>> {
+    let mut matches = Vec::new();
+    let mut seen = HashSet::new();
+    for mut normalized in expand_braces(&normalize_separators(g)) {
+        // A trailing `**` only matches directories, `_compiled/**` is meant to cover the files too
+        if normalized == "**" || normalized.ends_with("/**") {
+            normalized.push_str("/*");
+        }
+        let glb = format!(
+            "{}/{}",
+            // The base directory exists as given, only the glob is matched ignoring case
+            glob::Pattern::escape(&base_dir.to_string_lossy()),
+            case_insensitive_globify(&normalized)
+        );
+        let Ok(paths) = glob_with(
+            &glb,
+            MatchOptions {
+                case_sensitive: false,
+                require_literal_separator: false,
+                require_literal_leading_dot: false,
+            },
+        ) else {
+            eprintln!("[WARN] {} is not a valid glob, skipping it.", g);
+            return None;
+        };
+
+        for path in paths.flatten() {
+            let Some(relative) = relative_to_base(&path, base_dir) else {
+                eprintln!(
+                    "[WARN] {} is outside of the base directory {}, skipping it.",
+                    path.display(),
+                    base_dir.display()
+                );
+                continue;
+            };
+            if !relative.as_os_str().is_empty() && seen.insert(relative.clone()) {
+                matches.push(relative);
+            }
+        }
+    }
+    if matches.is_empty() {
+        eprintln!("[WARN] {} matched nothing in {}.", g, base_dir.display());
+    }
+    Some(matches)
+}
+
+/// Expands a leading `~` to the home directory and resolves wildcards like `builds/*/assets`
+/// to the one directory they match, matching none or several is an error.
+fn resolve_base_dir(base_dir: &Path) -> Result<PathBuf> {
+    let mut resolved = base_dir.to_path_buf();
+    if let Ok(rest) = base_dir.strip_prefix("~") {
+        if let Some(home) = std::env::var_os("HOME") {
+            resolved = PathBuf::from(home).join(rest);
+        }
+    }
+
+    let pattern = resolved.to_string_lossy().into_owned();
+    if !pattern.contains(['*', '?', '[']) {
+        return Ok(resolved);
+    }
+    let matches: Vec<PathBuf> = match glob::glob(&pattern) {
+        Ok(paths) => paths.flatten().filter(|p| p.is_dir()).collect(),
+        Err(e) => bail!("The base directory {} is not a valid glob: {}", pattern, e),
+    };
+    match matches.as_slice() {
+        [dir] => {
+            eprintln!("[INFO] Using {} as the base directory.", dir.display());
+            Ok(dir.clone())
+        }
+        [] => bail!("The base directory {} matches no directory.", pattern),
+        _ => bail!(
+            "The base directory {} is ambiguous, it matches {}.",
+            pattern,
+            matches
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// The error for a file of the tree that could not be read while building the catalog,
+/// telling a file that was removed since the tree was built apart from other I/O errors.
+fn read_error(path: &Path, e: io::Error) -> anyhow::Error {
+    if e.kind() == io::ErrorKind::NotFound {
+        return anyhow!(
+            "{} was removed while the archive was built, the source changed during the build. Try again once nothing writes to it.",
+            path.display()
+        );
+    }
+    anyhow::Error::new(e).context(format!("Could not read {}", path.display()))
+}
+
+/// `path` relative to `base`, or `None` if it is not inside of it.
+/// `./` components are ignored and the others compared ignoring case,
+/// since glob drops the former and the globs built from `base` match any case.
+fn relative_to_base(path: &Path, base: &Path) -> Option<PathBuf> {
+    let mut path = path.components().filter(|c| *c != Component::CurDir);
+    for b in base.components().filter(|c| *c != Component::CurDir) {
+        if !path.next()?.as_os_str().eq_ignore_ascii_case(b.as_os_str()) {
+            return None;
+        }
+    }
+    Some(path.collect())
+}
+
+/// Finds a directory and a file among siblings that share a name once uppercased,
+/// loaders resolve such a pair unpredictably.
+fn find_dir_file_clash(children: &[FileSystemNode]) -> Option<(&PathBuf, &PathBuf)> {
+    let dirs: HashMap<String, &PathBuf> = children
+        .iter()
+        .filter_map(|c| match c {
+            FileSystemNode::Directory { name, path, .. } => Some((name.to_ascii_uppercase(), path)),
+            FileSystemNode::File { .. } => None,
+        })
+        .collect();
+
+    children.iter().find_map(|c| match c {
+        FileSystemNode::File { name, path, .. } => {
+            dirs.get(&name.to_ascii_uppercase()).map(|dir| (*dir, path))
+        }
+        FileSystemNode::Directory { .. } => None,
+    })
+}
+
 fn is_on_level(filters: &Vec<Vec<String>>, search_term: &str, level: i32) -> bool {
     if level == -1 {
         return true;
@@ -497,20 +2330,808 @@ fn is_on_level(filters: &Vec<Vec<String>>, search_term: &str, level: i32) -> boo
             return true;
         }
     }
-    return false;
+    false
+}
+
+/// Replaces backslashes used as path separators, like in `textures\**\*.tga` from a Windows
+/// user, with `/`. In a glob without any `/` every backslash is taken as a separator,
+/// otherwise only the ones that do not escape a metacharacter.
+fn normalize_separators(glob: &str) -> Cow<'_, str> {
+    if !glob.contains('\\') {
+        return Cow::Borrowed(glob);
+    }
+    let normalized = if !glob.contains('/') {
+        glob.replace('\\', "/")
+    } else {
+        let mut normalized = String::new();
+        let mut chars = glob.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, chars.peek()) {
+                ('\\', Some(&e @ ('*' | '?' | '[' | ']' | '\\'))) => {
+                    normalized.push(c);
+                    normalized.push(e);
+                    chars.next();
+                }
+                ('\\', _) => normalized.push('/'),
+                _ => normalized.push(c),
+            }
+        }
+        normalized
+    };
+    if normalized != glob {
+        eprintln!(
+            "[WARN] {} uses \\ as path separator, reading it as {}.",
+            glob, normalized
+        );
+    }
+    Cow::Owned(normalized)
 }
 
+/// One glob per alternative of the `{a,b}` groups in `glob`, so `*.{tex,tga}` gives `*.tex` and
+/// `*.tga`. Groups may be nested or follow each other, a group without a comma is kept as it is
+/// and braces inside a character class or after an escaping backslash are literal.
+fn expand_braces(glob: &str) -> Vec<String> {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if !cfg!(windows) => i += 1,
+            '[' => i = class_end(&chars, i),
+            '{' => {
+                if let Some((close, commas)) = brace_group(&chars, i) {
+                    if !commas.is_empty() {
+                        let prefix: String = chars[..i].iter().collect();
+                        let suffix: String = chars[close + 1..].iter().collect();
+                        let bounds: Vec<usize> =
+                            [i].into_iter().chain(commas).chain([close]).collect();
+                        return bounds
+                            .windows(2)
+                            .flat_map(|w| {
+                                let alternative: String = chars[w[0] + 1..w[1]].iter().collect();
+                                expand_braces(&format!("{}{}{}", prefix, alternative, suffix))
+                            })
+                            .collect();
+                    }
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    vec![glob.to_string()]
+}
+
+/// Where the group opened at `open` is closed and the commas separating its alternatives.
+fn brace_group(chars: &[char], open: usize) -> Option<(usize, Vec<usize>)> {
+    let mut depth = 0;
+    let mut commas = Vec::new();
+    let mut i = open;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if !cfg!(windows) => i += 1,
+            '[' => i = class_end(chars, i),
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((i, commas));
+                }
+            }
+            ',' if depth == 1 => commas.push(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Where the character class opened at `open` ends, a `]` right after `[` or `[!` belongs to it.
+fn class_end(chars: &[char], open: usize) -> usize {
+    let mut i = open + 1;
+    if chars.get(i) == Some(&'!') {
+        i += 1;
+    }
+    if chars.get(i) == Some(&']') {
+        i += 1;
+    }
+    while i < chars.len() && chars[i] != ']' {
+        i += 1;
+    }
+    i
+}
+
+/// Makes every letter of the glob `input` match both cases. Character classes like `[0-9]`
+/// are kept as they are and a backslash makes the next character literal, except on Windows
+/// where it separates paths.
 fn case_insensitive_globify(input: &str) -> String {
     let mut s = String::new();
-    for c in input.chars() {
-        if c.is_alphabetic() {
-            s.push('[');
-            s.push(c.to_ascii_lowercase());
-            s.push(c.to_ascii_uppercase());
-            s.push(']');
-        } else {
-            s.push(c);
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if !cfg!(windows) => match chars.next() {
+                Some(e) if e.is_alphabetic() => push_both_cases(&mut s, e),
+                Some(e @ ('*' | '?' | '[' | ']')) => {
+                    s.push('[');
+                    s.push(e);
+                    s.push(']');
+                }
+                Some(e) => s.push(e),
+                None => s.push('\\'),
+            },
+            '[' => {
+                s.push('[');
+                // A `]` right after `[` or `[!` belongs to the class instead of closing it
+                if let Some(negation) = chars.next_if_eq(&'!') {
+                    s.push(negation);
+                }
+                if let Some(bracket) = chars.next_if_eq(&']') {
+                    s.push(bracket);
+                }
+                for c in chars.by_ref() {
+                    s.push(c);
+                    if c == ']' {
+                        break;
+                    }
+                }
+            }
+            c if c.is_alphabetic() => push_both_cases(&mut s, c),
+            c => s.push(c),
         }
     }
     s
 }
+
+fn push_both_cases(s: &mut String, c: char) {
+    s.push('[');
+    s.push(c.to_ascii_lowercase());
+    s.push(c.to_ascii_uppercase());
+    s.push(']');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own below the system temp directory for every test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vdfs-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_file(dir: &Path, path: &str, contents: &[u8]) {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    /// Saves `vdfs` into `dir` and reads it back.
+    fn round_trip(vdfs: &Vdfs, dir: &Path, options: &ReadOptions) -> Vdfs {
+        let path = dir.join("ROUNDTRIP.VDF");
+        vdfs.save_to_file(&path).unwrap();
+        Vdfs::from_file_with(&path, options).unwrap()
+    }
+
+    fn entry<'a>(vdfs: &'a Vdfs, path: &str) -> &'a VDFSCatalogEntry {
+        &vdfs.catalog_dirs[vdfs.find_entry(path).unwrap()]
+    }
+
+    fn script(
+        base_dir: &Path,
+        include: &[&'static str],
+        exclude: &[&'static str],
+    ) -> VdfsScript<'static> {
+        VdfsScript {
+            comment: "".into(),
+            base_dir: base_dir.to_path_buf(),
+            file_path: PathBuf::new(),
+            file_include_globs: include.iter().map(|g| (*g).into()).collect(),
+            priority: Vec::new(),
+            priority_placement: PriorityPlacement::default(),
+            path_rewrites: Vec::new(),
+            file_mapping: Vec::new(),
+            file_exclude_globs: exclude.iter().map(|g| (*g).into()).collect(),
+            precedence: Precedence::default(),
+        }
+    }
+
+    /// The logical paths of all files `script` selects, sorted.
+    fn script_files(script: &VdfsScript, base_dir_override: &Option<PathBuf>) -> Vec<String> {
+        let tree = script_tree(script, base_dir_override, &mut TreeFilter::default()).unwrap();
+        let mut files: Vec<_> = tree
+            .flatten()
+            .into_iter()
+            .filter(|(_, is_dir)| !is_dir)
+            .map(|(path, _)| path)
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn recover_clamps_a_forged_entry_count() {
+        let dir = temp_dir("forged-count");
+        let vdfs = VdfsBuilder::new(BuildOptions::default())
+            .add_raw("A.TXT", b"hello".to_vec())
+            .build()
+            .unwrap();
+        let path = dir.join("FORGED.VDF");
+        vdfs.save_to_file(&path).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[272..276].copy_from_slice(&0x0400_0000_u32.to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+
+        let options = ReadOptions {
+            recover: true,
+            ..Default::default()
+        };
+        let read = Vdfs::from_file_with(&path, &options).unwrap();
+        assert_eq!(read.header.num_files, 1);
+        assert_eq!(read.read_entry("A.TXT"), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn update_keeps_the_entries_of_the_archive() {
+        let dir = temp_dir("update");
+        let src = dir.join("src");
+        write_file(&src, "a.txt", b"old");
+        fs::create_dir_all(src.join("EMPTY")).unwrap();
+        let mut permissions = fs::metadata(src.join("a.txt")).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(src.join("a.txt"), permissions).unwrap();
+        let options = BuildOptions {
+            preserve_attributes: true,
+            keep_empty_dirs: true,
+            metadata: vec![("key".to_string(), "value".to_string())],
+            ..Default::default()
+        };
+        let packed = round_trip(
+            &Vdfs::from_dir(&src, options).unwrap(),
+            &dir,
+            &ReadOptions::default(),
+        );
+
+        let new = dir.join("new");
+        write_file(&new, "b.txt", b"new");
+        let tree = Vdfs::tree_from_dir(&new, &mut TreeFilter::default());
+        let updated = packed
+            .update_with(tree, OnConflict::Error, &BuildOptions::default())
+            .unwrap();
+        let read = round_trip(&updated, &dir, &ReadOptions::default());
+
+        assert_eq!(entry(&read, "A.TXT").attributes, ATTRIBUTE_READONLY);
+        assert!(entry(&read, "EMPTY").is_dir);
+        assert_eq!(read.read_entry("A.TXT"), Some(&b"old"[..]));
+        assert_eq!(read.read_entry("B.TXT"), Some(&b"new"[..]));
+        assert_eq!(read.metadata(), packed.metadata());
+    }
+
+    #[test]
+    fn json_scripts_may_contain_escapes() {
+        let json = r#"{
+            "comment": "Built by \"vdfs\"\n",
+            "base_dir": "src",
+            "file_path": "OUT.VDF",
+            "file_include_globs": ["textures\/*.tex", "A*.txt"]
+        }"#;
+        let script = VdfsScript::from_json(json).unwrap();
+        assert_eq!(script.comment, "Built by \"vdfs\"\n");
+        assert_eq!(script.file_include_globs, ["textures/*.tex", "A*.txt"]);
+    }
+
+    #[test]
+    fn preserved_case_is_checked_by_uppercase_name() {
+        let options = BuildOptions {
+            preserve_case: true,
+            sorted_catalog: true,
+            ..Default::default()
+        };
+        VdfsBuilder::new(options)
+            .add_raw("b.txt", b"b".to_vec())
+            .add_raw("C.TXT", b"c".to_vec())
+            .build()
+            .unwrap();
+
+        let options = BuildOptions {
+            preserve_case: true,
+            unique_basenames: true,
+            ..Default::default()
+        };
+        let clash = VdfsBuilder::new(options)
+            .add_raw("d1/a.txt", b"a".to_vec())
+            .add_raw("d2/A.TXT", b"a".to_vec())
+            .build();
+        assert!(clash.is_err());
+    }
+
+    #[test]
+    fn entries_display_their_name_in_their_codepage() {
+        let entry = VDFSCatalogEntry {
+            name: encode_entry_name("テ.TXT", Encoding::ShiftJis),
+            encoding: Encoding::ShiftJis,
+            ..Default::default()
+        };
+        assert!(entry.to_string().starts_with("Name: テ.TXT\n"));
+    }
+
+    #[test]
+    fn raw_entries_are_laid_out_like_the_build() {
+        let dir = temp_dir("raw-entry");
+        let options = BuildOptions {
+            reserve: Some(100),
+            sorted_catalog: true,
+            unique_basenames: true,
+            ..Default::default()
+        };
+        let mut vdfs = VdfsBuilder::new(options)
+            .add_raw("B.TXT", b"b".to_vec())
+            .add_raw("D/X.TXT", b"x".to_vec())
+            .build()
+            .unwrap();
+        vdfs.add_raw_entry("A.TXT", b"a".to_vec()).unwrap();
+        assert!(vdfs.add_raw_entry("x.txt", b"x".to_vec()).is_err());
+
+        let read = round_trip(&vdfs, &dir, &ReadOptions::default());
+        let paths: Vec<_> = read.entries().map(|(path, _)| path).collect();
+        assert_eq!(paths, ["A.TXT", "B.TXT", "D", "D/X.TXT"]);
+        assert_eq!(read.header.size, 3);
+        assert_eq!(read.data.len(), 100);
+    }
+
+    #[test]
+    fn filtered_trees_keep_matched_directories_only() {
+        let dir = temp_dir("filtered");
+        write_file(&dir, "a.txt", b"a");
+        write_file(&dir, "skipped/b.txt", b"b");
+        fs::create_dir_all(dir.join("EMPTY")).unwrap();
+        let filter = vec![vec!["a.txt".to_string()], vec!["EMPTY".to_string()]];
+        let tree =
+            build_file_system_tree_filtered(&dir, -1, &filter, &mut TreeFilter::default()).unwrap();
+        let paths: Vec<_> = tree.flatten().into_iter().map(|(path, _)| path).collect();
+        assert_eq!(paths, ["EMPTY", "a.txt"]);
+
+        for (keep_empty_dirs, entries) in [(true, 2), (false, 1)] {
+            let options = BuildOptions {
+                keep_empty_dirs,
+                ..Default::default()
+            };
+            let vdfs = Vdfs::from_tree(tree.clone(), &options).unwrap();
+            assert_eq!(vdfs.catalog_dirs.len(), entries);
+        }
+    }
+
+    #[test]
+    fn one_file_archive_is_byte_exact() {
+        let dir = temp_dir("one-file");
+        write_file(&dir, "a.txt", b"hi");
+        let options = BuildOptions {
+            no_timestamp: true,
+            ..Default::default()
+        };
+        let mut written = Vec::new();
+        Vdfs::from_dir(&dir, options)
+            .unwrap()
+            .write_to(&mut written)
+            .unwrap();
+
+        let mut expected = vec![0x1A; 256];
+        expected.extend_from_slice(b"PSVDSC_V2.00\n\r\n\r");
+        // Files, entries, timestamp, data size, catalog offset and version
+        for field in [1u32, 1, 0, 2, 296, 80] {
+            expected.extend_from_slice(&field.to_le_bytes());
+        }
+        let mut name = [b' '; 64];
+        name[..5].copy_from_slice(b"A.TXT");
+        expected.extend_from_slice(&name);
+        // Offset right behind the catalog, size, LastFile and attributes
+        for field in [376u32, 2, 0x4000_0000, 0] {
+            expected.extend_from_slice(&field.to_le_bytes());
+        }
+        expected.extend_from_slice(b"hi");
+        assert_eq!(written, expected);
+    }
+
+    #[test]
+    fn empty_subfolders_are_left_out_by_default() {
+        let dir = temp_dir("empty-subfolder");
+        let src = dir.join("src");
+        write_file(&src, "a.txt", b"a");
+        write_file(&src, "d/b.txt", b"b");
+        fs::create_dir_all(src.join("d/EMPTY")).unwrap();
+
+        let vdfs = Vdfs::from_dir(&src, BuildOptions::default()).unwrap();
+        let read = round_trip(&vdfs, &dir, &ReadOptions::default());
+        let paths: Vec<_> = read.entries().map(|(path, _)| path).collect();
+        assert_eq!(paths, ["D", "A.TXT", "D/B.TXT"]);
+        assert_eq!(entry(&read, "D").next_index, 2);
+        let data = read.entry_data(entry(&read, "D/B.TXT"));
+        assert_eq!(data.as_deref(), Some(&b"b"[..]));
+
+        // Kept, the empty folder points past the catalog, where loaders find no children
+        let options = BuildOptions {
+            keep_empty_dirs: true,
+            ..Default::default()
+        };
+        let vdfs = Vdfs::from_dir(&src, options).unwrap();
+        let read = round_trip(&vdfs, &dir, &ReadOptions::default());
+        let end = read.catalog_dirs.len() as u32;
+        assert_eq!(entry(&read, "D/EMPTY").next_index, end);
+    }
+
+    #[test]
+    fn unknown_entry_bits_survive_a_rewrite() {
+        let dir = temp_dir("entry-bits");
+        let vdfs = VdfsBuilder::new(BuildOptions::default())
+            .add_raw("A.TXT", b"a".to_vec())
+            .build()
+            .unwrap();
+        let path = dir.join("BITS.VDF");
+        vdfs.save_to_file(&path).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        let typ = HEADER_SIZE as usize + 72;
+        bytes[typ..typ + 4].copy_from_slice(&0x4000_1234_u32.to_le_bytes());
+        bytes[typ + 4..typ + 8].copy_from_slice(&0xDEAD_BEEF_u32.to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+
+        let read = Vdfs::from_file(&path).unwrap();
+        let mut written = Vec::new();
+        read.write_to(&mut written).unwrap();
+        assert_eq!(written, bytes);
+    }
+
+    #[test]
+    fn directories_link_to_their_own_children() {
+        let dir = temp_dir("traversal");
+        let src = dir.join("src");
+        let files = [
+            "a.txt",
+            "d1/b.txt",
+            "d1/sub/c.txt",
+            "d2/e.txt",
+            "d2/sub/f.txt",
+            "d2/sub/deeper/g.txt",
+        ];
+        for file in files {
+            write_file(&src, file, file.as_bytes());
+        }
+
+        let vdfs = Vdfs::from_dir(&src, BuildOptions::default()).unwrap();
+        let read = round_trip(&vdfs, &dir, &ReadOptions::default());
+        let mut packed: Vec<_> = read
+            .entries()
+            .filter(|(_, e)| !e.is_dir)
+            .map(|(path, _)| path.to_string())
+            .collect();
+        packed.sort();
+        let mut expected: Vec<_> = files.iter().map(|f| f.to_ascii_uppercase()).collect();
+        expected.sort();
+        assert_eq!(packed, expected);
+        for file in files {
+            assert_eq!(read.read_entry(file), Some(file.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn catalog_follows_the_reference_order() {
+        let dir = temp_dir("reference-order");
+        let src = dir.join("src");
+        for file in [
+            "b.txt",
+            "a_b.txt",
+            "a.txt",
+            "a-b.txt",
+            "ab.txt",
+            "_x.txt",
+            "ä.txt",
+            "zdir/z.txt",
+            "adir/a.txt",
+        ] {
+            write_file(&src, file, b"x");
+        }
+        let root_order = |sorted_catalog| {
+            let options = BuildOptions {
+                sorted_catalog,
+                ..Default::default()
+            };
+            let vdfs = Vdfs::from_dir(&src, options).unwrap();
+            let read = round_trip(&vdfs, &dir, &ReadOptions::default());
+            read.entries()
+                .map(|(path, _)| path.to_string())
+                .filter(|path| !path.contains('/'))
+                .collect::<Vec<_>>()
+        };
+
+        // Directories first, then byte by byte in Windows-1252 with only ASCII uppercased
+        assert_eq!(
+            root_order(false),
+            ["ADIR", "ZDIR", "A-B.TXT", "A.TXT", "AB.TXT", "A_B.TXT", "B.TXT", "_X.TXT", "ä.TXT"]
+        );
+        assert_eq!(
+            root_order(true),
+            ["A-B.TXT", "A.TXT", "AB.TXT", "ADIR", "A_B.TXT", "B.TXT", "ZDIR", "_X.TXT", "ä.TXT"]
+        );
+    }
+
+    #[test]
+    fn read_archives_are_written_back_unchanged() {
+        let dir = temp_dir("golden");
+        let src = dir.join("src");
+        write_file(&src, "a.txt", b"first");
+        write_file(&src, "d/b.txt", b"second");
+        write_file(&src, "d/e/c.txt", b"third");
+        let options = BuildOptions {
+            metadata: vec![("mod".to_string(), "golden".to_string())],
+            ..Default::default()
+        };
+        let path = dir.join("GOLDEN.VDF");
+        Vdfs::from_dir(&src, options)
+            .unwrap()
+            .save_to_file(&path)
+            .unwrap();
+        let golden = fs::read(&path).unwrap();
+
+        let read = Vdfs::from_file(&path).unwrap();
+        assert!(!read.catalog_gap.is_empty());
+        let mut written = Vec::new();
+        read.write_to(&mut written).unwrap();
+        assert_eq!(written, golden);
+    }
+
+    #[test]
+    fn signature_inside_the_comment_is_not_the_header() {
+        let dir = temp_dir("comment-signature");
+        let comment = "Packed with PSVDSC_V2.00\n\r\n\r inside the comment";
+        let vdfs = VdfsBuilder::new(BuildOptions::default())
+            .add_raw("A.TXT", b"a".to_vec())
+            .build()
+            .unwrap()
+            .add_comment(Some(comment));
+        let path = dir.join("COMMENT.VDF");
+        vdfs.save_to_file(&path).unwrap();
+        let archive = fs::read(&path).unwrap();
+
+        // Once where the header belongs and once pushed back by bytes of another tool
+        for leading in [0, 300] {
+            let mut bytes = vec![0xFF; leading];
+            bytes.extend_from_slice(&archive);
+            fs::write(&path, bytes).unwrap();
+            let read = Vdfs::from_file(&path).unwrap();
+            assert_eq!(read.header_info().comment, comment);
+            assert_eq!(read.read_entry("A.TXT"), Some(&b"a"[..]));
+        }
+    }
+
+    #[test]
+    fn zero_file_archive_reads_and_extracts_nothing() {
+        let dir = temp_dir("zero-files");
+        let vdfs = VdfsBuilder::new(BuildOptions::default()).build().unwrap();
+        let read = round_trip(&vdfs, &dir, &ReadOptions::default());
+        assert_eq!(read.header.num_files, 0);
+        assert_eq!(read.entries().count(), 0);
+
+        let output = dir.join("out");
+        read.extract(&output).unwrap();
+        assert!(!output.exists() || fs::read_dir(&output).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn sorted_catalog_is_strictly_increasing_per_directory() {
+        let dir = temp_dir("sorted-catalog");
+        let src = dir.join("src");
+        for file in [
+            "Zeta.txt",
+            "alpha.txt",
+            "_under.txt",
+            "b/inner.txt",
+            "B_/x.txt",
+            "b/A/deep.txt",
+            "b/a.txt",
+            "c.TXT",
+            "b.txt",
+        ] {
+            write_file(&src, file, b"x");
+        }
+        let options = BuildOptions {
+            sorted_catalog: true,
+            ..Default::default()
+        };
+        let vdfs = Vdfs::from_dir(&src, options).unwrap();
+        let read = round_trip(&vdfs, &dir, &ReadOptions::default());
+
+        let entries = &read.catalog_dirs;
+        for pair in entries.windows(2) {
+            if pair[0].parent_id == pair[1].parent_id {
+                assert!(pair[0].name < pair[1].name);
+            }
+        }
+        // Every entry was compared with its sibling except the first of each directory
+        let dirs = entries.iter().filter(|e| e.is_dir).count();
+        let compared = entries
+            .windows(2)
+            .filter(|pair| pair[0].parent_id == pair[1].parent_id)
+            .count();
+        assert_eq!(compared, entries.len() - dirs - 1);
+    }
+
+    #[test]
+    fn precedence_decides_between_include_and_exclude() {
+        let dir = temp_dir("precedence");
+        for file in ["both.txt", "include.txt", "exclude.txt", "neither.txt"] {
+            write_file(&dir, file, b"x");
+        }
+        let mut script = script(
+            &dir,
+            &["both.txt", "include.txt"],
+            &["both.txt", "exclude.txt"],
+        );
+
+        script.precedence = Precedence::Exclude;
+        assert_eq!(script_files(&script, &None), ["include.txt"]);
+        script.precedence = Precedence::Include;
+        assert_eq!(script_files(&script, &None), ["both.txt", "include.txt"]);
+    }
+
+    #[test]
+    fn small_flat_tree_matches_the_general_walk() {
+        let dir = temp_dir("small-flat");
+        for file in ["b.txt", "A.TXT", "c_d.bin", "c-d.bin", "ä.txt"] {
+            write_file(&dir, file, file.as_bytes());
+        }
+        let small = small_flat_tree(&dir, &TreeFilter::default()).unwrap();
+        let general = build_file_system_tree(&dir, -1, &mut TreeFilter::default());
+        assert_eq!(small, general);
+
+        let archive = |tree| {
+            let options = BuildOptions {
+                no_timestamp: true,
+                ..Default::default()
+            };
+            let mut bytes = Vec::new();
+            Vdfs::from_tree(tree, &options)
+                .unwrap()
+                .write_to(&mut bytes)
+                .unwrap();
+            bytes
+        };
+        assert_eq!(archive(small), archive(general));
+    }
+
+    #[test]
+    fn kept_empty_folders_get_valid_entries() {
+        let dir = temp_dir("keep-empty");
+        let src = dir.join("src");
+        write_file(&src, "a.txt", b"a");
+        write_file(&src, "d/b.txt", b"b");
+        fs::create_dir_all(src.join("EMPTY")).unwrap();
+        fs::create_dir_all(src.join("d2/e")).unwrap();
+        let options = BuildOptions {
+            keep_empty_dirs: true,
+            ..Default::default()
+        };
+        let vdfs = Vdfs::from_dir(&src, options).unwrap();
+        let read = round_trip(&vdfs, &dir, &ReadOptions::default());
+
+        let paths: Vec<_> = read.entries().map(|(path, _)| path).collect();
+        assert_eq!(paths, ["D", "D2", "EMPTY", "A.TXT", "D/B.TXT", "D2/E"]);
+        // Empty directories point past the catalog, where loaders find no children
+        let end = read.catalog_dirs.len() as u32;
+        let dir_bit = EntryType::Dir as u32;
+        let last_bit = EntryType::LastFile as u32;
+        assert_eq!(entry(&read, "EMPTY").typ, dir_bit);
+        assert_eq!(entry(&read, "EMPTY").next_index, end);
+        assert_eq!(entry(&read, "D2/E").typ, dir_bit | last_bit);
+        assert_eq!(entry(&read, "D2/E").next_index, end);
+        assert_eq!(read.read_entry("D/B.TXT"), Some(&b"b"[..]));
+    }
+
+    #[test]
+    fn globs_recurse_and_expand_braces() {
+        let dir = temp_dir("globs");
+        for file in [
+            "Worlds/top.zen",
+            "mod/WORLDS/one.ZEN",
+            "mod/deep/er/worlds/two.zen",
+            "mod/worlds/skip.txt",
+            "other/none.zen",
+            "textures/a.tga",
+            "Sounds/b.WAV",
+            "textures/c.wav",
+            "music/d.wav",
+            "sounds/e.mp3",
+        ] {
+            write_file(&dir, file, b"x");
+        }
+
+        let recursive = script(&dir, &["**/worlds/*.ZEN"], &[]);
+        assert_eq!(
+            script_files(&recursive, &None),
+            [
+                "Worlds/top.zen",
+                "mod/WORLDS/one.ZEN",
+                "mod/deep/er/worlds/two.zen"
+            ]
+        );
+        let braces = script(&dir, &["{textures,sounds}/*.{tga,wav}"], &[]);
+        assert_eq!(
+            script_files(&braces, &None),
+            ["Sounds/b.WAV", "textures/a.tga", "textures/c.wav"]
+        );
+    }
+
+    #[test]
+    fn base_dir_override_replaces_the_script_base() {
+        let dir = temp_dir("base-override");
+        let src = dir.join("src");
+        write_file(&src, "a.txt", b"a");
+        write_file(&src, "d/b.txt", b"b");
+        write_file(&dir, "parent.txt", b"p");
+        let script = script(&src, &["*.txt", "d/*.txt"], &[]);
+
+        let trailing_slash = PathBuf::from(format!("{}/", src.display()));
+        assert_eq!(
+            script_files(&script, &Some(trailing_slash)),
+            ["a.txt", "d/b.txt"]
+        );
+        assert_eq!(script_files(&script, &Some(dir.clone())), ["parent.txt"]);
+        assert_eq!(
+            script_files(&script, &Some(src.clone())),
+            ["a.txt", "d/b.txt"]
+        );
+        assert_eq!(script_files(&script, &None), ["a.txt", "d/b.txt"]);
+    }
+
+    #[test]
+    fn extract_rebuilds_the_hierarchy_and_refuses_cut_off_data() {
+        let dir = temp_dir("extract");
+        let src = dir.join("src");
+        write_file(&src, "a.txt", b"first");
+        write_file(&src, "d/e/b.txt", b"second");
+        let vdfs = Vdfs::from_dir(&src, BuildOptions::default()).unwrap();
+        let path = dir.join("EXTRACT.VDF");
+        vdfs.save_to_file(&path).unwrap();
+
+        let output = dir.join("out");
+        Vdfs::from_file(&path).unwrap().extract(&output).unwrap();
+        assert_eq!(fs::read(output.join("A.TXT")).unwrap(), b"first");
+        assert_eq!(fs::read(output.join("D/E/B.TXT")).unwrap(), b"second");
+
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let cut_off = dir.join("cut-off");
+        assert!(Vdfs::from_file(&path).unwrap().extract(&cut_off).is_err());
+        assert!(!cut_off.join("A.TXT").exists());
+    }
+
+    #[test]
+    fn name_problems_are_judged_by_the_encoded_name() {
+        let dir = temp_dir("name-problems");
+        let fits = format!("{}.TXT", "\u{3042}".repeat(30));
+        let too_long = format!("{}.TXT", "\u{3044}".repeat(31));
+        write_file(&dir, &fits, b"a");
+        write_file(&dir, &too_long, b"b");
+        let tree = build_file_system_tree(&dir, -1, &mut TreeFilter::default());
+
+        // Two bytes a character in Shift-JIS, so 64 and 66 bytes
+        let problems = tree.name_problems(Encoding::ShiftJis);
+        assert_eq!(problems, [(too_long.clone(), "is longer than 64 bytes")]);
+        let problems = tree.name_problems(Encoding::Cp1252);
+        assert_eq!(
+            problems,
+            [
+                (fits, "contains characters outside of the codepage"),
+                (too_long, "contains characters outside of the codepage"),
+            ]
+        );
+    }
+
+    #[test]
+    fn long_names_are_cut_off_between_characters() {
+        // 1 + 32 * 2 bytes in Shift-JIS, the last character only fits half
+        let long = format!("A{}", "\u{3042}".repeat(32));
+        let name = encode_entry_name(&long, Encoding::ShiftJis);
+        assert_eq!(name[63], b' ');
+        let expected = format!("A{}", "\u{3042}".repeat(31));
+        assert_eq!(decode_entry_name(&name, Encoding::ShiftJis), expected);
+    }
+}
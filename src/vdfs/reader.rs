@@ -0,0 +1,528 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    codepage::{DecodeFallback, Encoding},
+    decode_entry_name,
+    filetree::FileSystemNode,
+    Endianness, EntryType, RebuildLayout, VDFSCatalogEntry, VDFSHeader, Vdfs, CATALOG_ENTRY_SIZE,
+    HEADER_SIZE, SIGNATURE_SIZE, VERSION,
+};
+
+/// The part of the signature every archive of the original tools starts with, the line breaks
+/// that follow vary between tools
+const SIGNATURE_PREFIX: &[u8] = b"PSVDSC_V2.00";
+/// The signature follows the 256 byte comment
+const SIGNATURE_OFFSET: usize = 256;
+/// How far into the file the header may be pushed by bytes other tools put in front of it
+const MAX_LEADING_BYTES: usize = 512;
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Settings for opening existing archives.
+#[derive(Debug, Default, Clone)]
+pub struct ReadOptions {
+    /// How bytes of the comment that are not valid in `encoding` are decoded
+    pub comment_fallback: DecodeFallback,
+    /// Codepage of the names and the comment
+    pub encoding: Encoding,
+    /// Fail instead of warning when the version field is not 80, or `version` when it is set
+    pub strict_version: bool,
+    /// The version archives of an engine fork have, see `Vdfs::with_version`
+    pub version: Option<u32>,
+    /// The signature archives of an engine fork have, see `Vdfs::with_signature`.
+    /// Without it the header is found by `PSVDSC_V2.00`.
+    pub signature: Option<[u8; SIGNATURE_SIZE]>,
+    /// Read whatever is left of a truncated archive instead of failing on it
+    pub recover: bool,
+    /// Compare the attributes of every file entry to the CRC32 of its contents,
+    /// for archives packed with `crc_in_attributes`
+    pub check_crc: bool,
+}
+
+impl Vdfs {
+    /// Opens an existing archive, the catalog is read from the offset stored in the header.
+    pub fn from_file(path: &PathBuf) -> Result<Self> {
+        Vdfs::from_file_with(path, &ReadOptions::default())
+    }
+
+    /// The number of files the header of the archive at `path` declares, read without parsing
+    /// the catalog so a progress display can be set up before `from_file_with` is done.
+    pub fn read_file_count(path: &Path) -> Result<u32> {
+        let file =
+            File::open(path).with_context(|| format!("Could not read {}", path.display()))?;
+        let mut bytes = Vec::new();
+        file.take((MAX_LEADING_BYTES + HEADER_SIZE as usize) as u64)
+            .read_to_end(&mut bytes)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+
+        let Some(header_start) = find_header_start(&bytes, SIGNATURE_PREFIX) else {
+            bail!("{} has no VDFS signature.", path.display());
+        };
+        if bytes.len() < header_start + HEADER_SIZE as usize {
+            bail!("{} is too short to be an archive.", path.display());
+        }
+        Ok(read_u32(&bytes, header_start + 276))
+    }
+
+    pub fn from_file_with(path: &PathBuf, options: &ReadOptions) -> Result<Self> {
+        let bytes = fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+
+        let signature = match &options.signature {
+            Some(signature) => signature.as_slice(),
+            None => SIGNATURE_PREFIX,
+        };
+        let Some(header_start) = find_header_start(&bytes, signature) else {
+            bail!("{} has no VDFS signature.", path.display());
+        };
+        if header_start != 0 {
+            eprintln!(
+                "[INFO] {} has {} bytes in front of the header, skipping them.",
+                path.display(),
+                header_start
+            );
+        }
+        // Offsets in the header and catalog are relative to the start of the header
+        let bytes = &bytes[header_start..];
+
+        if bytes.len() < HEADER_SIZE as usize {
+            bail!(
+                "{} is too short to be an archive ({} bytes).",
+                path.display(),
+                bytes.len()
+            );
+        }
+
+        let mut header = VDFSHeader::default();
+        header.comment.copy_from_slice(&bytes[..256]);
+        header.comment_fallback = options.comment_fallback;
+        header.encoding = options.encoding;
+        header
+            .comment_text()
+            .with_context(|| format!("Could not decode the comment of {}", path.display()))?;
+        header.signature.copy_from_slice(&bytes[256..272]);
+        header.num_files = read_u32(bytes, 272);
+        header.num_entries = read_u32(bytes, 276);
+        header.timestamp = read_u32(bytes, 280);
+        header.size = read_u32(bytes, 284);
+        header.catalog_offset = read_u32(bytes, 288);
+        header.version = read_u32(bytes, 292);
+        let version = options.version.unwrap_or(VERSION);
+        if header.version() != version {
+            if options.strict_version {
+                bail!(
+                    "{} has version {}, only version {} is supported.",
+                    path.display(),
+                    header.version(),
+                    version
+                );
+            }
+            eprintln!(
+                "[WARN] {} has version {} instead of {}, offsets may be read wrong.",
+                path.display(),
+                header.version(),
+                version
+            );
+        }
+
+        let mut catalog_start = header.catalog_offset as usize;
+        if header.num_files == 0 {
+            // Nothing is read from the catalog, so tools that leave the offset at 0
+            // or point it past the end of an empty archive are accepted
+            catalog_start = catalog_start.clamp(HEADER_SIZE as usize, bytes.len());
+        } else if catalog_start > bytes.len() {
+            bail!(
+                "The catalog offset {} of {} lies past the end of the file ({} bytes).",
+                header.catalog_offset,
+                path.display(),
+                bytes.len()
+            );
+        }
+        // Checked against the file length before anything is allocated for the entries,
+        // so a forged count cannot make the reader parse billions of them
+        let max_entries = (bytes.len() - catalog_start) / CATALOG_ENTRY_SIZE as usize;
+        let mut entries = header.num_files as usize;
+        if entries > max_entries {
+            if !options.recover {
+                bail!(
+                    "The catalog of {} declares {} entries at offset {}, but the file only has room for {}.",
+                    path.display(),
+                    header.num_files,
+                    header.catalog_offset,
+                    max_entries
+                );
+            }
+            // Keep the entries that were written completely
+            eprintln!(
+                "[WARN] The catalog of {} is cut off after {} of {} entries.",
+                path.display(),
+                max_entries,
+                header.num_files
+            );
+            entries = max_entries;
+            // Everything after the catalog is read as data, so the offsets have to agree
+            header.num_files = entries as u32;
+        }
+        let catalog_end = catalog_start + entries * CATALOG_ENTRY_SIZE as usize;
+
+        let mut catalog_dirs: Vec<VDFSCatalogEntry> = bytes[catalog_start..catalog_end]
+            .chunks_exact(CATALOG_ENTRY_SIZE as usize)
+            .map(|raw| {
+                let mut e = VDFSCatalogEntry::default();
+                e.name.copy_from_slice(&raw[..64]);
+                e.name_utf8 = decode_entry_name(&e.name, options.encoding);
+                e.encoding = options.encoding;
+                e.next_index = read_u32(raw, 64);
+                e.size = read_u32(raw, 68);
+                e.typ = read_u32(raw, 72);
+                e.attributes = read_u32(raw, 76);
+                e.is_dir = e.typ & EntryType::Dir as u32 != 0;
+                e
+            })
+            .collect();
+        link_parents(&mut catalog_dirs);
+
+        let mut vdfs = Vdfs {
+            curr_pos: header.size,
+            header,
+            fs: FileSystemNode::Directory {
+                name: path.file_name().unwrap().to_string_lossy().into_owned(),
+                path: path.to_path_buf(),
+                children: Vec::new(),
+                level: -1,
+                is_last: false,
+            },
+            catalog_dirs,
+            data: bytes[catalog_end..].to_vec(),
+            entry_paths: Vec::new(),
+            path_index: HashMap::new(),
+            dedup: false,
+            crc_in_attributes: options.check_crc,
+            parallel_read: false,
+            preserve_attributes: false,
+            preserve_case: false,
+            endianness: Endianness::Little,
+            opened: true,
+            chunks: Vec::new(),
+            progress: None,
+            rebuild: RebuildLayout::default(),
+            stats: Default::default(),
+            catalog_gap: bytes
+                .get(HEADER_SIZE as usize..catalog_start)
+                .unwrap_or_default()
+                .to_vec(),
+        };
+        vdfs.index_paths();
+        vdfs.restore_tree();
+        match vdfs.data_length_difference() {
+            0 => {}
+            extra if extra > 0 => eprintln!(
+                "[WARN] {} has {} bytes after the file data, it was appended to or padded.",
+                path.display(),
+                extra
+            ),
+            missing => eprintln!(
+                "[WARN] {} is {} bytes shorter than its header says, it is probably truncated.",
+                path.display(),
+                -missing
+            ),
+        }
+        if options.recover {
+            vdfs.report_truncated();
+        }
+        if options.check_crc {
+            vdfs.report_crc_mismatches();
+        }
+
+        Ok(vdfs)
+    }
+}
+
+impl Vdfs {
+    /// Rebuilds `fs` from the catalog in catalog order, for archives read from a file.
+    /// Files get their logical path as `path` and no contents, they are read from `data`.
+    pub(super) fn restore_tree(&mut self) {
+        let FileSystemNode::Directory { children, .. } = &mut self.fs else {
+            return;
+        };
+        let entries = &self.catalog_dirs;
+
+        // Children are attached bottom-up, so even a forged catalog with absurdly deep
+        // nesting does not need any recursion
+        let mut depth: Vec<Option<usize>> = vec![None; entries.len()];
+        for i in 0..entries.len() {
+            let mut chain = vec![i];
+            let mut parent = entries[i].parent_id;
+            while parent >= 0 && depth[parent as usize].is_none() && chain.len() <= entries.len() {
+                chain.push(parent as usize);
+                parent = entries[parent as usize].parent_id;
+            }
+            let mut d = match parent {
+                -1 => 0,
+                p => depth[p as usize].unwrap_or_default() + 1,
+            };
+            for j in chain.into_iter().rev() {
+                if depth[j].is_none() {
+                    depth[j] = Some(d);
+                    d += 1;
+                }
+            }
+        }
+
+        let mut child_indices: Vec<Vec<usize>> = vec![Vec::new(); entries.len()];
+        let mut root_indices = Vec::new();
+        for (i, entry) in entries.iter().enumerate() {
+            match entry.parent_id {
+                -1 => root_indices.push(i),
+                p => child_indices[p as usize].push(i),
+            }
+        }
+
+        let mut nodes: Vec<Option<FileSystemNode>> = vec![None; entries.len()];
+        let mut order: Vec<usize> = (0..entries.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(depth[i]));
+        let take_children = |nodes: &mut Vec<Option<FileSystemNode>>, indices: &[usize]| {
+            let mut children: Vec<FileSystemNode> =
+                indices.iter().filter_map(|&c| nodes[c].take()).collect();
+            if let Some(
+                FileSystemNode::Directory { is_last, .. } | FileSystemNode::File { is_last, .. },
+            ) = children.last_mut()
+            {
+                *is_last = true;
+            }
+            children
+        };
+        for i in order {
+            let entry = &entries[i];
+            let level = depth[i].unwrap_or_default() as i32;
+            let name = entry.name_utf8.clone();
+            let path = PathBuf::from(&self.entry_paths[i]);
+            nodes[i] = Some(if entry.is_dir {
+                FileSystemNode::Directory {
+                    name,
+                    path,
+                    children: take_children(&mut nodes, &child_indices[i]),
+                    level,
+                    is_last: false,
+                }
+            } else {
+                FileSystemNode::File {
+                    name,
+                    path,
+                    contents: None,
+                    is_last: false,
+                    level,
+                }
+            });
+        }
+        *children = take_children(&mut nodes, &root_indices);
+    }
+
+    /// Logical paths of the files whose data does not fully lie within the archive.
+    pub fn truncated_entries(&self) -> Vec<&str> {
+        self.entries()
+            .filter(|(_, entry)| !entry.is_dir && self.entry_data(entry).is_none())
+            .map(|(path, _)| path)
+            .collect()
+    }
+
+    /// Checks that the header counts match the catalog, that every directory points at a run of
+    /// children ending in the `LastFile` flag, that every entry belongs to exactly one such run
+    /// and that the data of every file lies within the archive. Fails on the first inconsistency.
+    pub fn verify(&self) -> Result<()> {
+        let entries = &self.catalog_dirs;
+        if self.header.num_files as usize != entries.len() {
+            bail!(
+                "The header declares {} entries, but the catalog has {}.",
+                self.header.num_files,
+                entries.len()
+            );
+        }
+        let files = entries.iter().filter(|e| !e.is_dir).count();
+        if self.header.num_entries as usize != files {
+            bail!(
+                "The header declares {} files, but the catalog has {}.",
+                self.header.num_entries,
+                files
+            );
+        }
+
+        let describe = |i: usize| format!("entry {} ({})", i, entries[i].name_utf8);
+        let mut visited = vec![false; entries.len()];
+        // The first entry of every run of siblings, starting with the root
+        let mut runs = VecDeque::new();
+        if !entries.is_empty() {
+            runs.push_back((0, None));
+        }
+        while let Some((start, parent)) = runs.pop_front() {
+            let mut i = start;
+            loop {
+                if i >= entries.len() {
+                    let owner = match parent {
+                        Some(parent) => describe(parent),
+                        None => "the root".to_string(),
+                    };
+                    bail!(
+                        "The children of {} starting at entry {} run past the end of the catalog without a LastFile flag.",
+                        owner,
+                        start
+                    );
+                }
+                if visited[i] {
+                    bail!(
+                        "The catalog lists {} in more than one directory.",
+                        describe(i)
+                    );
+                }
+                visited[i] = true;
+
+                let entry = &entries[i];
+                if entry.is_dir {
+                    let child = entry.next_index as usize;
+                    // Empty directories point just past the catalog
+                    if child < entries.len() {
+                        runs.push_back((child, Some(i)));
+                    } else if child > entries.len() {
+                        bail!(
+                            "The directory {} points at entry {}, the catalog only has {}.",
+                            describe(i),
+                            child,
+                            entries.len()
+                        );
+                    }
+                } else if self.entry_data(entry).is_none() {
+                    bail!(
+                        "The data of {} at offset {} with {} bytes lies outside of the archive.",
+                        describe(i),
+                        entry.next_index,
+                        entry.size
+                    );
+                }
+                if entry.typ & EntryType::LastFile as u32 != 0 {
+                    break;
+                }
+                i += 1;
+            }
+        }
+        if let Some(i) = visited.iter().position(|v| !v) {
+            bail!("The catalog lists {} in no directory.", describe(i));
+        }
+        Ok(())
+    }
+
+    /// How many bytes the data after the catalog is longer (positive) or shorter (negative)
+    /// than the size in the header, 0 for an intact archive.
+    pub fn data_length_difference(&self) -> i64 {
+        self.data.len() as i64 - self.header.size as i64
+    }
+
+    /// The CRC32 stored in the attributes of the file at `path`, only meaningful for archives
+    /// packed with `crc_in_attributes`. `None` for directories and unknown paths.
+    pub fn stored_crc(&self, path: &str) -> Option<u32> {
+        let entry = &self.catalog_dirs[self.find_entry(path)?];
+        (!entry.is_dir).then_some(entry.attributes)
+    }
+
+    /// Logical paths of the files whose contents do not match the CRC32 in their attributes,
+    /// together with the stored and the actual CRC. Truncated files are left out.
+    pub fn crc_mismatches(&self) -> Vec<(&str, u32, u32)> {
+        self.entries()
+            .filter(|(_, entry)| !entry.is_dir)
+            .filter_map(|(path, entry)| {
+                let actual = crc32fast::hash(&self.entry_data(entry)?);
+                (actual != entry.attributes).then_some((path, entry.attributes, actual))
+            })
+            .collect()
+    }
+
+    fn report_crc_mismatches(&self) {
+        let mismatches = self.crc_mismatches();
+        if mismatches.is_empty() {
+            eprintln!("[INFO] The CRC of every file matches.");
+            return;
+        }
+        for (path, stored, actual) in &mismatches {
+            eprintln!(
+                "[WARN] The CRC of {} is {:08X}, but {:08X} is stored.",
+                path, actual, stored
+            );
+        }
+        eprintln!(
+            "[WARN] {} files do not match their CRC, they are corrupt or the archive has no CRCs.",
+            mismatches.len()
+        );
+    }
+
+    fn report_truncated(&self) {
+        let truncated = self.truncated_entries();
+        let files = self.catalog_dirs.iter().filter(|e| !e.is_dir).count();
+        if truncated.is_empty() {
+            eprintln!("[INFO] The data of all {} files is complete.", files);
+            return;
+        }
+        eprintln!(
+            "[WARN] {} of {} files are complete, {} are truncated:",
+            files - truncated.len(),
+            files,
+            truncated.len()
+        );
+        for path in truncated {
+            eprintln!("[WARN]     {}", path);
+        }
+    }
+}
+
+/// Looks for `signature` in the first few hundred bytes,
+/// returns where the header (the comment in front of the signature) starts.
+fn find_header_start(bytes: &[u8], signature: &[u8]) -> Option<usize> {
+    // Where the signature belongs, a comment that happens to contain it cannot get in the way
+    if bytes.get(SIGNATURE_OFFSET..SIGNATURE_OFFSET + signature.len()) == Some(signature) {
+        return Some(0);
+    }
+
+    let end = bytes
+        .len()
+        .min(SIGNATURE_OFFSET + MAX_LEADING_BYTES + signature.len());
+    let candidates: Vec<usize> = bytes
+        .get(SIGNATURE_OFFSET..end)?
+        .windows(signature.len())
+        .enumerate()
+        .filter(|(_, w)| *w == signature)
+        .map(|(start, _)| start)
+        .collect();
+    // A match inside the comment of a later match is part of that comment, not a header
+    candidates.iter().copied().find(|&start| {
+        !candidates
+            .iter()
+            .any(|&later| later > start && later - start <= SIGNATURE_OFFSET)
+    })
+}
+
+/// Restores `parent_id` by following the directory child links,
+/// the root's children start at index 0 and each sibling group ends with `LastFile`.
+fn link_parents(catalog: &mut [VDFSCatalogEntry]) {
+    let mut visited = vec![false; catalog.len()];
+    let mut queue = VecDeque::new();
+    queue.push_back((0_usize, -1));
+
+    while let Some((mut i, parent)) = queue.pop_front() {
+        while i < catalog.len() && !visited[i] {
+            visited[i] = true;
+            catalog[i].parent_id = parent;
+            if catalog[i].is_dir {
+                queue.push_back((catalog[i].next_index as usize, i as i32));
+            }
+            if catalog[i].typ & EntryType::LastFile as u32 != 0 {
+                break;
+            }
+            i += 1;
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::{anyhow, bail, Result};
+use regex::{Regex, RegexBuilder};
+use std::str::FromStr;
+
+/// A sed style `s/pattern/replacement/` rule applied to the logical path of every file.
+///
+/// Any character can stand in for the `/` delimiter and is escaped with a backslash inside
+/// the pattern or replacement. The replacement refers to groups as `$1` or `${name}`.
+/// Like sed only the first match is replaced unless the `g` flag is given, `i` ignores case.
+#[derive(Debug, Clone)]
+pub struct PathRewrite {
+    pattern: Regex,
+    replacement: String,
+    global: bool,
+}
+
+impl PathRewrite {
+    pub fn apply(&self, path: &str) -> String {
+        let limit = if self.global { 0 } else { 1 };
+        self.pattern
+            .replacen(path, limit, self.replacement.as_str())
+            .into_owned()
+    }
+}
+
+impl FromStr for PathRewrite {
+    type Err = anyhow::Error;
+
+    fn from_str(rule: &str) -> Result<Self> {
+        let mut chars = rule.chars();
+        if chars.next() != Some('s') {
+            bail!(
+                "{} is not a rewrite rule like s/pattern/replacement/.",
+                rule
+            );
+        }
+        let Some(delimiter) = chars.next() else {
+            bail!(
+                "{} is not a rewrite rule like s/pattern/replacement/.",
+                rule
+            );
+        };
+
+        // Split at unescaped delimiters, an escaped one becomes part of the text
+        let mut parts = vec![String::new()];
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some(next) if next == delimiter => parts.last_mut().unwrap().push(next),
+                    Some(next) => {
+                        parts.last_mut().unwrap().push('\\');
+                        parts.last_mut().unwrap().push(next);
+                    }
+                    None => parts.last_mut().unwrap().push('\\'),
+                },
+                c if c == delimiter => parts.push(String::new()),
+                c => parts.last_mut().unwrap().push(c),
+            }
+        }
+        let [pattern, replacement, flags] = parts.as_slice() else {
+            bail!(
+                "{} is not a rewrite rule like s/pattern/replacement/.",
+                rule
+            );
+        };
+        if let Some(flag) = flags.chars().find(|f| !matches!(f, 'g' | 'i')) {
+            bail!(
+                "{} has the unknown flag {}, only g and i are supported.",
+                rule,
+                flag
+            );
+        }
+
+        let pattern = RegexBuilder::new(pattern)
+            .case_insensitive(flags.contains('i'))
+            .build()
+            .map_err(|e| anyhow!("{} has an invalid pattern: {}", rule, e))?;
+        Ok(PathRewrite {
+            pattern,
+            replacement: replacement.clone(),
+            global: flags.contains('g'),
+        })
+    }
+}
@@ -0,0 +1,9 @@
+use super::VdfsScript;
+use anyhow::Result;
+
+impl<'a> VdfsScript<'a> {
+    pub fn from_json(json_file: &'a str) -> Result<Self> {
+        let vdf: VdfsScript = serde_json::from_str(json_file)?;
+        Ok(vdf)
+    }
+}
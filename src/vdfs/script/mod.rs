@@ -1,15 +1,83 @@
-use std::path::PathBuf;
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+};
 
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 
+use super::{PathRewrite, Precedence, PriorityPlacement};
+
+mod json;
 mod vm;
 mod yaml;
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct VdfsScript<'a> {
-    pub comment: &'a str,
+    #[serde(borrow)]
+    pub comment: Cow<'a, str>,
     pub base_dir: PathBuf,
     pub file_path: PathBuf,
-    pub file_include_globs: Vec<&'a str>,
-    // pub file_exclude_globs: Vec<&'a str>,
+    #[serde(default, borrow)]
+    pub file_include_globs: Vec<Cow<'a, str>>,
+    /// Entries moved to the start or end of their directory, in this order
+    #[serde(default, borrow)]
+    pub priority: Vec<Cow<'a, str>>,
+    #[serde(default)]
+    pub priority_placement: PriorityPlacement,
+    /// `s/pattern/replacement/` rules for the logical paths, applied before the ones given on the command line
+    #[serde(default, borrow)]
+    pub path_rewrites: Vec<Cow<'a, str>>,
+    /// Files placed at an explicit path in the archive, on top of what the globs select
+    #[serde(default, borrow)]
+    pub file_mapping: Vec<FileMapping<'a>>,
+    /// Files matching one of these are left out, even when an include glob matches them too
+    /// unless `precedence` says otherwise
+    #[serde(default, borrow)]
+    pub file_exclude_globs: Vec<Cow<'a, str>>,
+    #[serde(default)]
+    pub precedence: Precedence,
+}
+
+/// A file below `base_dir` and the path it gets in the archive.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct FileMapping<'a> {
+    pub source: PathBuf,
+    #[serde(borrow)]
+    pub path: Cow<'a, str>,
+}
+
+impl<'a> VdfsScript<'a> {
+    /// Parses the contents of the script at `path`, as json if its extension is `.json` and as yaml otherwise.
+    pub fn parse(path: &Path, source: &'a str) -> Result<Self> {
+        let is_json = path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("json"));
+        if is_json {
+            VdfsScript::from_json(source)
+        } else {
+            VdfsScript::from_yaml(source)
+        }
+    }
+
+    /// Checks the fields that cannot be overridden from the command line.
+    /// `base_dir` and `file_path` may be left empty when an override is given.
+    pub fn validate(&self) -> Result<()> {
+        if self.file_include_globs.is_empty() && self.file_mapping.is_empty() {
+            bail!("file_include_globs and file_mapping are empty, the archive would be empty.");
+        }
+        if self.file_include_globs.iter().any(|g| g.trim().is_empty()) {
+            bail!("file_include_globs contains an empty glob.");
+        }
+        if self.file_exclude_globs.iter().any(|g| g.trim().is_empty()) {
+            bail!("file_exclude_globs contains an empty glob.");
+        }
+        Ok(())
+    }
+
+    pub fn path_rewrites(&self) -> Result<Vec<PathRewrite>> {
+        self.path_rewrites.iter().map(|r| r.parse()).collect()
+    }
 }
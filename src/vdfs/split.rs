@@ -0,0 +1,206 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    slice,
+};
+
+use super::{
+    filetree::FileSystemNode, metadata, BuildOptions, Vdfs, CATALOG_ENTRY_SIZE, HEADER_SIZE,
+};
+
+/// The files of one directory, they are kept in the same volume whenever they fit.
+struct Group {
+    // Name and source path of every directory from the root down
+    dirs: Vec<(String, PathBuf)>,
+    files: Vec<(FileSystemNode, u64)>,
+}
+
+/// What goes into one volume and how large its archive will be.
+struct Volume {
+    tree: FileSystemNode,
+    dirs: HashSet<Vec<String>>,
+    size: u64,
+    // What every volume takes without any files: the header, the metadata block, the
+    // directory of `BuildOptions::root_name` and the embedded manifest
+    base: u64,
+    // `root_name` followed by a backslash, the start of every path in the manifest
+    root_prefix: Option<String>,
+    manifest: bool,
+}
+
+impl Volume {
+    fn new(options: &BuildOptions) -> Self {
+        let root_prefix = options.root_name.as_ref().map(|name| format!("{}\\", name));
+        let mut base = HEADER_SIZE as u64 + metadata::encode(&options.metadata).len() as u64;
+        if let Some(prefix) = &root_prefix {
+            base += CATALOG_ENTRY_SIZE as u64;
+            if options.embed_manifest {
+                base += prefix.len() as u64 + 2;
+            }
+        }
+        if options.embed_manifest {
+            base += CATALOG_ENTRY_SIZE as u64;
+        }
+        Volume {
+            tree: FileSystemNode::Directory {
+                name: String::new(),
+                path: PathBuf::new(),
+                children: Vec::new(),
+                level: -1,
+                is_last: false,
+            },
+            dirs: HashSet::new(),
+            size: base,
+            base,
+            root_prefix,
+            manifest: options.embed_manifest,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.size == self.base
+    }
+
+    /// How much the archive grows by adding `files` below `dirs`, the manifest lines included.
+    fn cost(&self, dirs: &[(String, PathBuf)], files: &[(FileSystemNode, u64)]) -> u64 {
+        let missing: Vec<usize> = (1..=dirs.len())
+            .filter(|&i| !self.dirs.contains(&dir_key(&dirs[..i])))
+            .collect();
+        let bytes: u64 = files.iter().map(|(_, size)| size).sum();
+        let mut cost = (missing.len() + files.len()) as u64 * CATALOG_ENTRY_SIZE as u64 + bytes;
+        if self.manifest {
+            // A line `DIR\` for every directory and `DIR\NAME<tab>SIZE` for every file
+            let prefix = |i: usize| {
+                self.root_prefix.as_ref().map_or(0, String::len)
+                    + dirs[..i]
+                        .iter()
+                        .map(|(name, _)| name.len() + 1)
+                        .sum::<usize>()
+            };
+            cost += missing.iter().map(|&i| prefix(i) as u64 + 2).sum::<u64>();
+            cost += files
+                .iter()
+                .map(|(file, size)| {
+                    (prefix(dirs.len()) + file.name().len() + size.to_string().len() + 3) as u64
+                })
+                .sum::<u64>();
+        }
+        cost
+    }
+
+    fn add(&mut self, dirs: &[(String, PathBuf)], file: (FileSystemNode, u64)) {
+        self.size += self.cost(dirs, slice::from_ref(&file));
+        for i in 1..=dirs.len() {
+            self.dirs.insert(dir_key(&dirs[..i]));
+        }
+        self.tree.insert_at(dirs, file.0);
+    }
+}
+
+/// Where volume `number` of an archive split for `output_file` goes,
+/// `archive.vdf` becomes `archive.001.vdf` for the first volume.
+pub fn volume_path(output_file: &Path, number: usize) -> PathBuf {
+    let stem = output_file
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let name = match output_file.extension() {
+        Some(ext) => format!("{}.{:03}.{}", stem, number, ext.to_string_lossy()),
+        None => format!("{}.{:03}", stem, number),
+    };
+    output_file.with_file_name(name)
+}
+
+fn dir_key(dirs: &[(String, PathBuf)]) -> Vec<String> {
+    dirs.iter()
+        .map(|(name, _)| name.to_ascii_uppercase())
+        .collect()
+}
+
+impl Vdfs {
+    /// Spreads the files of `tree` over as many archives as needed to keep each one
+    /// within `limit` bytes. Files of the same directory stay together unless the
+    /// directory alone is too large, empty directories are left out.
+    pub fn split_tree(
+        tree: &FileSystemNode,
+        limit: u64,
+        options: &BuildOptions,
+    ) -> Result<Vec<Vdfs>> {
+        let mut groups = Vec::new();
+        collect_groups(tree, &mut Vec::new(), &mut groups)?;
+
+        let mut volumes = vec![Volume::new(options)];
+        for group in groups {
+            let current = volumes.last().unwrap();
+            let empty = Volume::new(options);
+            if current.size + current.cost(&group.dirs, &group.files) > limit
+                && !current.is_empty()
+                && empty.size + empty.cost(&group.dirs, &group.files) <= limit
+            {
+                volumes.push(empty);
+            }
+
+            for file in group.files {
+                let current = volumes.last().unwrap();
+                if current.size + current.cost(&group.dirs, slice::from_ref(&file)) > limit {
+                    if current.is_empty() {
+                        bail!(
+                            "{} does not fit into a volume of {} bytes.",
+                            file.0.path().display(),
+                            limit
+                        );
+                    }
+                    volumes.push(Volume::new(options));
+                }
+                volumes.last_mut().unwrap().add(&group.dirs, file);
+            }
+        }
+
+        volumes
+            .into_iter()
+            .filter(|v| !v.is_empty())
+            .map(|v| Vdfs::from_tree(v.tree, options))
+            .collect()
+    }
+}
+
+/// Collects the files of every directory, depth first so related directories end up next to each other.
+fn collect_groups(
+    node: &FileSystemNode,
+    dirs: &mut Vec<(String, PathBuf)>,
+    groups: &mut Vec<Group>,
+) -> Result<()> {
+    let FileSystemNode::Directory { children, .. } = node else {
+        return Ok(());
+    };
+
+    let mut files = Vec::new();
+    for child in children {
+        if let FileSystemNode::File { path, contents, .. } = child {
+            let size = match contents {
+                Some(c) => c.len() as u64,
+                None => fs::metadata(path)
+                    .with_context(|| format!("Could not read {}", path.display()))?
+                    .len(),
+            };
+            files.push((child.clone(), size));
+        }
+    }
+    if !files.is_empty() {
+        groups.push(Group {
+            dirs: dirs.clone(),
+            files,
+        });
+    }
+
+    for child in children {
+        if let FileSystemNode::Directory { name, path, .. } = child {
+            dirs.push((name.clone(), path.clone()));
+            collect_groups(child, dirs, groups)?;
+            dirs.pop();
+        }
+    }
+    Ok(())
+}
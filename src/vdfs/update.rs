@@ -0,0 +1,168 @@
+use anyhow::{bail, Result};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use super::{filetree::FileSystemNode, metadata, BuildOptions, EntryType, Vdfs};
+
+/// What happens to a new file whose path the archive already has.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnConflict {
+    /// Fail without writing anything
+    #[default]
+    Error,
+    /// Keep the file of the archive
+    Skip,
+    /// Replace the file of the archive
+    Overwrite,
+}
+
+impl Vdfs {
+    /// The entries of the archive as a tree whose files carry their contents.
+    pub fn to_tree(&self) -> Result<FileSystemNode> {
+        let mut root = empty_dir(String::new(), PathBuf::new());
+        for (path, entry) in self.entries() {
+            let mut components: Vec<&str> = path.split('/').collect();
+            let name = components.pop().unwrap_or_default().to_string();
+            let node = if entry.is_dir {
+                empty_dir(name, PathBuf::from(path))
+            } else {
+                let Some(data) = self.entry_data(entry) else {
+                    bail!("The data of {} lies outside of the archive.", path);
+                };
+                FileSystemNode::File {
+                    name,
+                    path: PathBuf::from(path),
+                    contents: Some(data.to_vec()),
+                    is_last: false,
+                    level: 0,
+                }
+            };
+            root.insert_at(&dir_chain(&components), node);
+        }
+        Ok(root)
+    }
+
+    /// Builds a new archive holding the entries of this one and the files of `tree`,
+    /// `on_conflict` decides about files both of them have. The entries of this archive keep
+    /// their attributes and `typ` bits, its empty directories stay and so does the block between
+    /// the header and the catalog, unless `options` has metadata of its own.
+    pub fn update_with(
+        &self,
+        mut tree: FileSystemNode,
+        on_conflict: OnConflict,
+        options: &BuildOptions,
+    ) -> Result<Vdfs> {
+        let mut files = Vec::new();
+        tree.take_files("", &mut files);
+
+        let mut merged = self.to_tree()?;
+        let mut conflicts = Vec::new();
+        let mut replaced = HashSet::new();
+        for (path, file) in files {
+            if self.conflicts_with(&path) {
+                match on_conflict {
+                    OnConflict::Error => {
+                        conflicts.push(path);
+                        continue;
+                    }
+                    OnConflict::Skip => {
+                        eprintln!("[INFO] Keeping {} from the archive.", path);
+                        continue;
+                    }
+                    OnConflict::Overwrite => {
+                        eprintln!("[INFO] Replacing {}.", path);
+                        replaced.insert(path.to_ascii_uppercase());
+                    }
+                }
+            }
+            let mut components: Vec<&str> = path.split('/').collect();
+            components.pop();
+            merged.insert_at(&dir_chain(&components), file);
+        }
+
+        if !conflicts.is_empty() {
+            bail!(
+                "{} files already exist in the archive, nothing was written: {}",
+                conflicts.len(),
+                conflicts.join(", ")
+            );
+        }
+        let catalog_gap = if options.metadata.is_empty() {
+            self.catalog_gap.clone()
+        } else {
+            metadata::encode(&options.metadata)
+        };
+        // Only the files of `tree` were taken over, so every empty directory left is one of ours
+        let mut updated = Vdfs::from_tree_with_gap(merged, options, catalog_gap, false)?;
+        updated.carry_entry_bits(self, &replaced);
+        Ok(updated)
+    }
+
+    /// Copies the attributes and the `typ` bits other than Dir and LastFile of every entry
+    /// `from` has at the same path, except for the `replaced` files.
+    fn carry_entry_bits(&mut self, from: &Vdfs, replaced: &HashSet<String>) {
+        let layout = EntryType::Dir as u32 | EntryType::LastFile as u32;
+        for (path, entry) in self.entry_paths.iter().zip(self.catalog_dirs.iter_mut()) {
+            if replaced.contains(&path.to_ascii_uppercase()) {
+                continue;
+            }
+            let Some(old) = from.find_entry(path).map(|i| &from.catalog_dirs[i]) else {
+                continue;
+            };
+            if old.is_dir == entry.is_dir {
+                entry.attributes = old.attributes;
+                entry.typ = (entry.typ & layout) | (old.typ & !layout);
+            }
+        }
+    }
+
+    /// Adds everything inside the directory `dir` to this archive, keeping its comment, timestamp,
+    /// version, signature, metadata and what `update_with` keeps of the entries. Fails without changing anything when the archive already has one of the files.
+    /// The catalog is laid out anew, so the child index of every directory and the `LastFile`
+    /// flags come out like those of an archive packed in one go.
+    pub fn append(&mut self, dir: &Path) -> Result<()> {
+        if !dir.is_dir() {
+            bail!("{} is not a directory.", dir.display());
+        }
+        let mut options = BuildOptions {
+            encoding: self.header.encoding,
+            crc_in_attributes: self.crc_in_attributes,
+            ..Default::default()
+        };
+        let tree = Vdfs::tree_from_dir(&dir.to_path_buf(), &mut options.tree_filter);
+        let mut appended = self.update_with(tree, OnConflict::Error, &options)?;
+        appended.header.comment = self.header.comment;
+        appended.header.timestamp = self.header.timestamp;
+        appended.header.version = self.header.version;
+        appended.header.signature = self.header.signature;
+        *self = appended;
+        Ok(())
+    }
+
+    /// Whether the archive has an entry at `path` or a file where `path` needs a directory.
+    fn conflicts_with(&self, path: &str) -> bool {
+        let components: Vec<&str> = path.split('/').collect();
+        self.contains(path)
+            || (1..components.len()).any(|i| {
+                self.find_entry(&components[..i].join("/"))
+                    .is_some_and(|index| !self.catalog_dirs[index].is_dir)
+            })
+    }
+}
+
+fn empty_dir(name: String, path: PathBuf) -> FileSystemNode {
+    FileSystemNode::Directory {
+        name,
+        path,
+        children: Vec::new(),
+        level: 0,
+        is_last: false,
+    }
+}
+
+/// The name and logical path of every directory on the way to `components`.
+fn dir_chain(components: &[&str]) -> Vec<(String, PathBuf)> {
+    (0..components.len())
+        .map(|i| (components[i].to_string(), components[..=i].join("/").into()))
+        .collect()
+}